@@ -0,0 +1,148 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::domain::order::Price;
+
+const DAY: u64 = 24 * 60 * 60;
+// how many days of traded notional count towards a tier
+const VOLUME_WINDOW_DAYS: u64 = 30;
+// seconds between tier recalculations, fills in between only count from the next one on
+const RECALCULATION_INTERVAL: u64 = 60 * 60;
+
+// A VIP level. Its discounts come off every instrument's flat maker and taker fee.
+#[derive(Serialize)]
+pub struct FeeTier {
+    pub level: u8,
+    // trailing 30 day notional, both sides of a fill count, needed to reach this tier
+    pub min_volume: Price,
+    pub maker_discount_bps: Decimal,
+    pub taker_discount_bps: Decimal,
+}
+
+pub const FEE_TIERS: &[FeeTier] = &[
+    FeeTier {
+        level: 0,
+        min_volume: dec!(0),
+        maker_discount_bps: dec!(0),
+        taker_discount_bps: dec!(0),
+    },
+    FeeTier {
+        level: 1,
+        min_volume: dec!(1_000_000),
+        maker_discount_bps: dec!(0.5),
+        taker_discount_bps: dec!(0.5),
+    },
+    FeeTier {
+        level: 2,
+        min_volume: dec!(10_000_000),
+        maker_discount_bps: dec!(1),
+        taker_discount_bps: dec!(1.5),
+    },
+    FeeTier {
+        level: 3,
+        min_volume: dec!(100_000_000),
+        maker_discount_bps: dec!(2),
+        taker_discount_bps: dec!(2.5),
+    },
+];
+
+#[derive(Serialize)]
+pub struct FeeTierStatus {
+    pub user_id: String,
+    pub tier: &'static FeeTier,
+    pub next_tier: Option<&'static FeeTier>,
+    // trailing 30 day notional as of the last recalculation, the tier is based on this
+    pub volume_30d: Price,
+    pub recalculated_at: u64,
+    pub next_recalculation: u64,
+}
+
+pub struct FeeTierQuery {
+    pub user_id: String,
+
+    pub responder: Option<oneshot::Sender<FeeTierStatus>>,
+}
+
+#[derive(Default)]
+struct VolumeLedger {
+    // user id -> (day, notional traded that day), oldest first
+    daily: HashMap<String, VecDeque<(u64, Price)>>,
+    // user id -> (index into FEE_TIERS, 30 day notional) from the last recalculation
+    tiers: HashMap<String, (usize, Price)>,
+    recalculated_at: u64,
+}
+
+// Traded notional per user and the fee tier it earned. Every book records its fills here and
+// looks up the tiers of both sides before charging fees. Shared by all books on the book thread.
+#[derive(Clone, Default)]
+pub struct VolumeTiers {
+    ledger: Rc<RefCell<VolumeLedger>>,
+}
+
+impl VolumeTiers {
+    pub fn tier_of(&self, user_id: &str) -> &'static FeeTier {
+        let index = self
+            .ledger
+            .borrow()
+            .tiers
+            .get(user_id)
+            .map_or(0, |&(index, _)| index);
+        &FEE_TIERS[index]
+    }
+
+    pub fn record(&self, user_id: &str, notional: Price, now: u64) {
+        let day = now / DAY;
+        let mut ledger = self.ledger.borrow_mut();
+        let days = ledger.daily.entry(user_id.to_string()).or_default();
+        match days.back_mut() {
+            Some((last_day, volume)) if *last_day == day => *volume += notional,
+            _ => days.push_back((day, notional)),
+        }
+    }
+
+    // Called from the book thread's timer, only does anything once the interval is up.
+    pub fn recalculate_if_due(&self, now: u64) {
+        let mut ledger = self.ledger.borrow_mut();
+        if now < ledger.recalculated_at + RECALCULATION_INTERVAL {
+            return;
+        }
+
+        let first_day = (now / DAY).saturating_sub(VOLUME_WINDOW_DAYS - 1);
+        let mut tiers = HashMap::new();
+        ledger.daily.retain(|user_id, days| {
+            while days.front().is_some_and(|&(day, _)| day < first_day) {
+                days.pop_front();
+            }
+            let volume: Price = days.iter().map(|&(_, volume)| volume).sum();
+            let index = FEE_TIERS
+                .iter()
+                .rposition(|tier| volume >= tier.min_volume)
+                .unwrap_or(0);
+            tiers.insert(user_id.clone(), (index, volume));
+            !days.is_empty()
+        });
+
+        println!("[FEE TIERS] recalculated for {} users", tiers.len());
+        ledger.tiers = tiers;
+        ledger.recalculated_at = now;
+    }
+
+    pub fn status(&self, user_id: &str) -> FeeTierStatus {
+        let ledger = self.ledger.borrow();
+        let (index, volume) = ledger.tiers.get(user_id).copied().unwrap_or_default();
+        FeeTierStatus {
+            user_id: user_id.to_string(),
+            tier: &FEE_TIERS[index],
+            next_tier: FEE_TIERS.get(index + 1),
+            volume_30d: volume,
+            recalculated_at: ledger.recalculated_at,
+            next_recalculation: ledger.recalculated_at + RECALCULATION_INTERVAL,
+        }
+    }
+}
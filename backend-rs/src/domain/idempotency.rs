@@ -0,0 +1,39 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::domain::order::OrderResponse;
+
+// seconds a submission's result is kept under its idempotency key
+const RETENTION: u64 = 24 * 60 * 60;
+
+// Results of order submissions that came with an idempotency key, so a client retrying after a
+// timeout or a dropped connection gets the original result back instead of placing the order a
+// second time. Keys are scoped per user.
+#[derive(Default)]
+pub struct IdempotencyCache {
+    responses: HashMap<(String, String), OrderResponse>,
+    // (stored at, user id, key), oldest first
+    stored: VecDeque<(u64, String, String)>,
+}
+
+impl IdempotencyCache {
+    pub fn get(&self, user_id: &str, key: &str) -> Option<&OrderResponse> {
+        self.responses.get(&(user_id.to_string(), key.to_string()))
+    }
+
+    pub fn insert(&mut self, user_id: String, key: String, response: OrderResponse, now: u64) {
+        self.stored.push_back((now, user_id.clone(), key.clone()));
+        self.responses.insert((user_id, key), response);
+    }
+
+    // Called from the book thread's timer, forgets results older than the retention window.
+    pub fn expire(&mut self, now: u64) {
+        while let Some((stored_at, _, _)) = self.stored.front() {
+            if *stored_at + RETENTION > now {
+                break;
+            }
+            if let Some((_, user_id, key)) = self.stored.pop_front() {
+                self.responses.remove(&(user_id, key));
+            }
+        }
+    }
+}
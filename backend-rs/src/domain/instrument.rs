@@ -0,0 +1,257 @@
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+
+use crate::domain::fee_tier::FeeTier;
+use crate::domain::order::{Amount, Order, OrderType, Price, TrailingOffset};
+
+// How a taker's amount is shared out over the orders resting at one price level.
+#[derive(Clone, Copy, Serialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum MatchingAlgorithm {
+    // strictly in time priority
+    Fifo,
+    // in proportion to each order's visible amount, not listed on any instrument yet
+    #[allow(dead_code)]
+    ProRata,
+    // the order at the front of the level first gets this many percent of the taker's amount,
+    // the rest is shared out pro-rata over everyone else
+    TopOrderProRata {
+        top_order_percent: Decimal,
+    },
+}
+
+// A perpetual contract the exchange lists. Every instrument gets its own order book, oracle
+// stream, mark price and position tracker.
+#[derive(Serialize)]
+pub struct Instrument {
+    pub symbol: &'static str,
+    // price the simulated index of this market starts from
+    #[serde(skip)]
+    pub initial_price: Price,
+    // prices must be a multiple of this
+    pub tick_size: Price,
+    // highest price any order may carry, keeps every amount * price far from Decimal's limits
+    pub max_price: Price,
+    // amounts must be a multiple of this
+    pub lot_size: Amount,
+    pub min_size: Amount,
+    pub max_size: Amount,
+    // smallest amount * price an order with a known price may have
+    pub min_notional: Price,
+    // limit orders priced more than this many percent away from the index are rejected
+    pub price_band: Decimal,
+    // matching halts when the last trade price moves more than this many percent within
+    // `circuit_breaker_window` seconds, and resumes `circuit_breaker_cool_off` seconds later
+    pub circuit_breaker_move: Decimal,
+    pub circuit_breaker_window: u64,
+    pub circuit_breaker_cool_off: u64,
+    pub matching: MatchingAlgorithm,
+    // seconds the opening auction, and the re-opening auction after a halt, collect orders for
+    pub auction_duration: u64,
+    // charged on the notional of every fill in basis points, a negative fee is a rebate paid
+    // out of the exchange wallet. These are the tier 0 rates, VIP tiers get a discount on them.
+    pub maker_fee_bps: Decimal,
+    pub taker_fee_bps: Decimal,
+    // charged to both sides of the fills an auction uncross makes, neither side took liquidity
+    // from the other so nobody pays the taker rate there. VIP tiers get their maker discount on it,
+    // down to no fee at all.
+    pub auction_fee_bps: Decimal,
+}
+
+pub const INSTRUMENTS: &[Instrument] = &[
+    Instrument {
+        symbol: "BTC-PERP",
+        initial_price: dec!(60_000),
+        tick_size: dec!(0.01),
+        max_price: dec!(10_000_000),
+        lot_size: dec!(0.001),
+        min_size: dec!(0.001),
+        max_size: dec!(100),
+        min_notional: dec!(10),
+        price_band: dec!(10),
+        circuit_breaker_move: dec!(5),
+        circuit_breaker_window: 60,
+        circuit_breaker_cool_off: 30,
+        matching: MatchingAlgorithm::Fifo,
+        auction_duration: 10,
+        maker_fee_bps: dec!(-1),
+        taker_fee_bps: dec!(5),
+        auction_fee_bps: dec!(1),
+    },
+    Instrument {
+        symbol: "ETH-PERP",
+        initial_price: dec!(3_000),
+        tick_size: dec!(0.01),
+        max_price: dec!(1_000_000),
+        lot_size: dec!(0.01),
+        min_size: dec!(0.01),
+        max_size: dec!(1_000),
+        min_notional: dec!(10),
+        price_band: dec!(10),
+        circuit_breaker_move: dec!(7),
+        circuit_breaker_window: 60,
+        circuit_breaker_cool_off: 30,
+        matching: MatchingAlgorithm::TopOrderProRata {
+            top_order_percent: dec!(40),
+        },
+        auction_duration: 10,
+        maker_fee_bps: dec!(0),
+        taker_fee_bps: dec!(5),
+        auction_fee_bps: dec!(1),
+    },
+];
+
+pub fn instrument(symbol: &str) -> Option<&'static Instrument> {
+    INSTRUMENTS
+        .iter()
+        .find(|instrument| instrument.symbol == symbol)
+}
+
+fn is_multiple(value: Decimal, step: Decimal) -> bool {
+    (value % step).is_zero()
+}
+
+impl Instrument {
+    // Checks an order against the contract specs, so orders that would fragment the book into
+    // odd price levels or dust amounts never reach the book thread.
+    pub fn check_order(&self, order: &Order) -> Result<(), String> {
+        self.check_amount(order.amount)?;
+        if let Some(display_amount) = order.display_amount {
+            if !is_multiple(display_amount, self.lot_size) {
+                return Err(format!(
+                    "display amount {} is not a multiple of the lot size {}",
+                    display_amount, self.lot_size
+                ));
+            }
+        }
+
+        if matches!(order.order_type, OrderType::LIMIT | OrderType::STOP_LIMIT) {
+            self.check_price("price", order.price)?;
+        } else if order.price > self.max_price {
+            // market and stop market orders ignore their price, but funds are still reserved on it
+            return Err(format!(
+                "price {} is above the maximum price {}",
+                order.price, self.max_price
+            ));
+        }
+        if let Some(trigger_price) = order.trigger_price {
+            self.check_price("trigger price", trigger_price)?;
+        }
+        if let Some(protection_price) = order.protection_price {
+            self.check_price("protection price", protection_price)?;
+        }
+        if let Some(TrailingOffset::Absolute(offset)) = order.trailing_offset {
+            self.check_price("trailing offset", offset)?;
+        }
+        if let Some(brackets) = &order.brackets {
+            for price in [brackets.take_profit, brackets.stop_loss]
+                .into_iter()
+                .flatten()
+            {
+                self.check_price("take profit / stop loss", price)?;
+            }
+        }
+
+        // market and trailing orders only learn their price in the book
+        let reference_price = match order.order_type {
+            OrderType::LIMIT | OrderType::STOP_LIMIT => Some(order.price),
+            OrderType::STOP_MARKET => order.trigger_price,
+            OrderType::MARKET | OrderType::TRAILING_STOP => None,
+        };
+        if let Some(price) = reference_price {
+            self.check_notional(order.amount, price)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn check_amount(&self, amount: Amount) -> Result<(), String> {
+        if amount < self.min_size || amount > self.max_size {
+            return Err(format!(
+                "amount {} is outside {}'s size limits {} - {}",
+                amount, self.symbol, self.min_size, self.max_size
+            ));
+        }
+        if !is_multiple(amount, self.lot_size) {
+            return Err(format!(
+                "amount {} is not a multiple of the lot size {}",
+                amount, self.lot_size
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_price(&self, name: &str, price: Price) -> Result<(), String> {
+        if price > self.max_price {
+            return Err(format!(
+                "{} {} is above the maximum price {}",
+                name, price, self.max_price
+            ));
+        }
+        if !is_multiple(price, self.tick_size) {
+            return Err(format!(
+                "{} {} is not a multiple of the tick size {}",
+                name, price, self.tick_size
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn maker_fee(&self, notional: Price, tier: &FeeTier) -> Price {
+        notional * (self.maker_fee_bps - tier.maker_discount_bps) / dec!(10_000)
+    }
+
+    // a discount can take the taker fee down to nothing but never into a rebate
+    pub fn taker_fee(&self, notional: Price, tier: &FeeTier) -> Price {
+        notional * (self.taker_fee_bps - tier.taker_discount_bps).max(dec!(0)) / dec!(10_000)
+    }
+
+    // like the taker fee, a discount never turns it into a rebate, the exchange would pay out on
+    // both sides of the fill
+    pub fn auction_fee(&self, notional: Price, tier: &FeeTier) -> Price {
+        notional * (self.auction_fee_bps - tier.maker_discount_bps).max(dec!(0)) / dec!(10_000)
+    }
+
+    // The (maker, taker) fees of a fill. Fills made while uncrossing an auction charge both sides
+    // the auction fee, a post-only order that rested through the auction never pays the taker rate.
+    pub fn fill_fees(
+        &self,
+        notional: Price,
+        maker_tier: &FeeTier,
+        taker_tier: &FeeTier,
+        auction: bool,
+    ) -> (Price, Price) {
+        if auction {
+            return (
+                self.auction_fee(notional, maker_tier),
+                self.auction_fee(notional, taker_tier),
+            );
+        }
+        (
+            self.maker_fee(notional, maker_tier),
+            self.taker_fee(notional, taker_tier),
+        )
+    }
+
+    // Rounds an amount down to whole lots.
+    pub fn round_to_lot(&self, amount: Amount) -> Amount {
+        ((amount / self.lot_size).floor() * self.lot_size).normalize()
+    }
+
+    pub fn check_notional(&self, amount: Amount, price: Price) -> Result<(), String> {
+        let Some(notional) = amount.checked_mul(price) else {
+            return Err(format!(
+                "order value of {} @ {} is out of range",
+                amount, price
+            ));
+        };
+        if notional < self.min_notional {
+            return Err(format!(
+                "order value {} is below the minimum notional {}",
+                notional, self.min_notional
+            ));
+        }
+        Ok(())
+    }
+}
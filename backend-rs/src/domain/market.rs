@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::domain::fee_tier::{FeeTierQuery, VolumeTiers};
+use crate::domain::idempotency::IdempotencyCache;
+use crate::domain::instrument::INSTRUMENTS;
+use crate::domain::market_data::{DepthQuery, L3Query, MarketData};
+use crate::domain::order::{
+    reject, AmendOrder, BookError, CancelOrder, DeadManSwitch, MaintenanceQuery, MarketStatusQuery,
+    MassCancel, Order, OrderBook, Price, TriggerLevelQuery,
+};
+use crate::domain::order_history::{OrderRecord, OrderStatusQuery, OrdersQuery};
+use crate::domain::position::EngineEvent;
+use crate::domain::utils::now_secs;
+use crate::domain::wallet::{AvailableBalances, BalanceUpdate, WalletEvent};
+
+// The order books of every listed instrument, all owned by the book thread. New orders are
+// routed by their symbol, cancels, amends and queries by finding the book holding the order id.
+// Market data is published after every event that can touch a book. All books reserve funds
+// against one shared copy of the balances and count volume towards one set of fee tiers, so
+// both have to be built on the book thread.
+pub struct Markets {
+    books: HashMap<String, OrderBook>,
+    balances: AvailableBalances,
+    tiers: VolumeTiers,
+    // (user id, client order id) -> order id, entries of orders that left the book are pruned
+    // on the timer
+    client_ids: HashMap<(String, String), String>,
+    idempotency: IdempotencyCache,
+    // user id -> unix seconds all their orders get cancelled at unless the switch is refreshed
+    dead_man_switches: HashMap<String, u64>,
+}
+
+impl Markets {
+    pub fn new(
+        position_tx: mpsc::UnboundedSender<EngineEvent>,
+        wallet_tx: mpsc::UnboundedSender<WalletEvent>,
+        market_data_tx: mpsc::UnboundedSender<MarketData>,
+    ) -> Self {
+        let balances = AvailableBalances::new(wallet_tx);
+        let tiers = VolumeTiers::default();
+        let books = INSTRUMENTS
+            .iter()
+            .map(|instrument| {
+                (
+                    instrument.symbol.to_string(),
+                    OrderBook::new(
+                        instrument,
+                        position_tx.clone(),
+                        balances.clone(),
+                        tiers.clone(),
+                        market_data_tx.clone(),
+                    ),
+                )
+            })
+            .collect();
+
+        Markets {
+            books,
+            balances,
+            tiers,
+            client_ids: HashMap::new(),
+            idempotency: IdempotencyCache::default(),
+            dead_man_switches: HashMap::new(),
+        }
+    }
+
+    pub fn recalculate_fee_tiers(&self, now: u64) {
+        self.tiers.recalculate_if_due(now);
+    }
+
+    pub fn handle_fee_tier(&self, query: FeeTierQuery) {
+        if let Some(responder) = query.responder {
+            if responder.send(self.tiers.status(&query.user_id)).is_err() {
+                eprintln!("[FEE TIER RESPONSE ERROR] cannot send fee tier back");
+            }
+        }
+    }
+
+    pub fn reconcile_balance(&self, update: BalanceUpdate) {
+        self.balances.reconcile(update);
+    }
+
+    // A submission with an idempotency key that was seen before gets the stored result back.
+    // Otherwise the book's reply is caught on the way out and stored, every path through
+    // `insert_order` replies before returning.
+    pub fn insert_order(&mut self, mut order: Order) {
+        let Some(key) = order.idempotency_key.clone() else {
+            self.place_order(order);
+            return;
+        };
+
+        if let Some(response) = self.idempotency.get(&order.user_id, &key) {
+            println!(
+                "[IDEMPOTENT REPLAY] {} key {} -> order {}",
+                order.user_id, key, response.order_id
+            );
+            if let Some(responder) = order.responder.take() {
+                let _ = responder.send(response.clone());
+            }
+            return;
+        }
+
+        let (tx, mut rx) = oneshot::channel();
+        let responder = order.responder.replace(tx);
+        let user_id = order.user_id.clone();
+        self.place_order(order);
+
+        if let Ok(response) = rx.try_recv() {
+            self.idempotency
+                .insert(user_id, key, response.clone(), now_secs());
+            if let Some(responder) = responder {
+                let _ = responder.send(response);
+            }
+        }
+    }
+
+    fn place_order(&mut self, order: Order) {
+        if let Err(status) = self.claim_client_id(&order) {
+            reject(order, status);
+            return;
+        }
+        match self.books.get_mut(&order.symbol) {
+            Some(book) => {
+                book.insert_order(order);
+                book.publish_market_data();
+            }
+            None => {
+                let status = format!("rejected, unknown symbol {}", order.symbol);
+                reject(order, status);
+            }
+        }
+    }
+
+    // Both legs are placed in the first leg's book, the handler makes sure they share a symbol.
+    pub fn insert_oco(&mut self, first: Order, second: Order) {
+        let claimed = self
+            .claim_client_id(&first)
+            .and_then(|_| self.claim_client_id(&second));
+        if let Err(status) = claimed {
+            reject(first, status.clone());
+            reject(second, status);
+            return;
+        }
+        match self.books.get_mut(&first.symbol) {
+            Some(book) => {
+                book.insert_oco(first, second);
+                book.publish_market_data();
+            }
+            None => {
+                let status = format!("rejected, unknown symbol {}", first.symbol);
+                reject(first, status.clone());
+                reject(second, status);
+            }
+        }
+    }
+
+    // Maps the order's client order id to it, unless an open order of the same user has it.
+    fn claim_client_id(&mut self, order: &Order) -> Result<(), String> {
+        let Some(client_order_id) = &order.client_order_id else {
+            return Ok(());
+        };
+        let key = (order.user_id.clone(), client_order_id.clone());
+        if let Some(id) = self.client_ids.get(&key) {
+            if self.books.values().any(|book| book.is_live(id)) {
+                return Err(format!(
+                    "rejected, client order id {} is already used by open order {}",
+                    client_order_id, id
+                ));
+            }
+        }
+        self.client_ids.insert(key, order.id.clone());
+        Ok(())
+    }
+
+    pub fn handle_order_status(&mut self, query: OrderStatusQuery) {
+        let now = now_secs();
+        let result = if query.by_client_id {
+            self.books
+                .values_mut()
+                .filter_map(|book| book.order_status_by_client_id(&query.user_id, &query.id, now))
+                .max_by_key(|record| record.created_at)
+                .ok_or(BookError::OrderNotFound(query.id))
+        } else {
+            match self
+                .books
+                .values_mut()
+                .find_map(|book| book.order_status(&query.id, now))
+            {
+                Some(record) if record.user_id != query.user_id => {
+                    Err(BookError::NotOrderOwner(query.id))
+                }
+                Some(record) => Ok(record),
+                None => Err(BookError::OrderNotFound(query.id)),
+            }
+        };
+
+        if let Some(responder) = query.responder {
+            if responder.send(result).is_err() {
+                eprintln!("[ORDER STATUS RESPONSE ERROR] cannot send order status back");
+            }
+        }
+    }
+
+    // Open orders oldest first, finished ones most recently updated first.
+    pub fn handle_orders(&mut self, query: OrdersQuery) {
+        let now = now_secs();
+        let mut orders: Vec<OrderRecord> = self
+            .books
+            .values_mut()
+            .flat_map(|book| book.orders_of(&query.user_id, query.open, now))
+            .collect();
+        if query.open {
+            orders.sort_by_key(|record| record.created_at);
+        } else {
+            orders.sort_by_key(|record| std::cmp::Reverse(record.updated_at));
+        }
+
+        if let Some(responder) = query.responder {
+            if responder.send(orders).is_err() {
+                eprintln!("[ORDERS RESPONSE ERROR] cannot send orders back");
+            }
+        }
+    }
+
+    // Order id a cancel, amend or query refers to.
+    fn resolve(&self, id: String, user_id: &str, by_client_id: bool) -> Result<String, BookError> {
+        if !by_client_id {
+            return Ok(id);
+        }
+        self.client_ids
+            .get(&(user_id.to_string(), id.clone()))
+            .cloned()
+            .ok_or(BookError::OrderNotFound(id))
+    }
+
+    pub fn update_mark_price(&mut self, symbol: &str, mark_price: Price, index_price: Price) {
+        if let Some(book) = self.books.get_mut(symbol) {
+            book.update_mark_price(mark_price, index_price);
+            book.publish_market_data();
+        }
+    }
+
+    pub fn expire_orders(&mut self, now: u64) {
+        for book in self.books.values_mut() {
+            book.expire_orders(now);
+            book.settle_history(now);
+            book.publish_market_data();
+        }
+
+        let books = &self.books;
+        self.client_ids
+            .retain(|_, id| books.values().any(|book| book.is_live(id)));
+        self.idempotency.expire(now);
+    }
+
+    pub fn advance_phases(&mut self, now: u64) {
+        for book in self.books.values_mut() {
+            book.advance_phase(now);
+            book.publish_market_data();
+        }
+    }
+
+    pub fn handle_maintenance(&mut self, query: MaintenanceQuery) {
+        if let Some(book) = self.books.get_mut(&query.symbol) {
+            book.start_maintenance(now_secs(), query.duration);
+            book.publish_market_data();
+            if let Some(responder) = query.responder {
+                if responder.send(book.market_status()).is_err() {
+                    eprintln!("[MAINTENANCE RESPONSE ERROR] cannot send market status back");
+                }
+            }
+        }
+    }
+
+    pub fn handle_market_status(&self, query: MarketStatusQuery) {
+        if let (Some(book), Some(responder)) = (self.books.get(&query.symbol), query.responder) {
+            if responder.send(book.market_status()).is_err() {
+                eprintln!("[MARKET STATUS RESPONSE ERROR] cannot send market status back");
+            }
+        }
+    }
+
+    pub fn handle_depth(&self, query: DepthQuery) {
+        if let (Some(book), Some(responder)) = (self.books.get(&query.symbol), query.responder) {
+            if responder.send(book.depth_snapshot(query.levels)).is_err() {
+                eprintln!("[DEPTH RESPONSE ERROR] cannot send depth snapshot back");
+            }
+        }
+    }
+
+    pub fn handle_l3(&self, query: L3Query) {
+        if let (Some(book), Some(responder)) = (self.books.get(&query.symbol), query.responder) {
+            if responder.send(book.l3_snapshot()).is_err() {
+                eprintln!("[L3 RESPONSE ERROR] cannot send l3 snapshot back");
+            }
+        }
+    }
+
+    pub fn handle_cancel(&mut self, mut cancel: CancelOrder) {
+        cancel.id = match self.resolve(cancel.id, &cancel.user_id, cancel.by_client_id) {
+            Ok(id) => id,
+            Err(error) => return reply_error(cancel.responder, error),
+        };
+        match self.book_of(&cancel.id) {
+            Some(book) => {
+                book.handle_cancel(cancel);
+                book.publish_market_data();
+            }
+            None => reply_not_found(cancel.responder, cancel.id),
+        }
+    }
+
+    pub fn handle_mass_cancel(&mut self, mut cancel: MassCancel) {
+        let cancelled = self.cancel_all(&cancel);
+        if let Some(responder) = cancel.responder.take() {
+            if responder.send(cancelled).is_err() {
+                eprintln!("[MASS CANCEL RESPONSE ERROR] cannot send cancelled orders back");
+            }
+        }
+    }
+
+    fn cancel_all(&mut self, cancel: &MassCancel) -> Vec<String> {
+        let mut cancelled = Vec::new();
+        for book in self.books.values_mut() {
+            if cancel
+                .symbol
+                .as_ref()
+                .is_some_and(|symbol| symbol != book.instrument.symbol)
+            {
+                continue;
+            }
+            cancelled.extend(book.cancel_all(cancel));
+            book.publish_market_data();
+        }
+        println!(
+            "[MASS CANCELLED] {} orders of {}",
+            cancelled.len(),
+            cancel.user_id
+        );
+        cancelled
+    }
+
+    pub fn handle_dead_man_switch(&mut self, switch: DeadManSwitch) {
+        let fires_at = if switch.timeout == 0 {
+            self.dead_man_switches.remove(&switch.user_id);
+            None
+        } else {
+            let fires_at = now_secs().saturating_add(switch.timeout);
+            self.dead_man_switches
+                .insert(switch.user_id.clone(), fires_at);
+            Some(fires_at)
+        };
+
+        if let Some(responder) = switch.responder {
+            if responder.send(fires_at).is_err() {
+                eprintln!("[DEAD MAN SWITCH RESPONSE ERROR] cannot send switch state back");
+            }
+        }
+    }
+
+    // Called from the book thread's timer, cancels everything of users whose switch ran out.
+    pub fn fire_dead_man_switches(&mut self, now: u64) {
+        let expired: Vec<String> = self
+            .dead_man_switches
+            .iter()
+            .filter(|&(_, &fires_at)| fires_at <= now)
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+
+        for user_id in expired {
+            self.dead_man_switches.remove(&user_id);
+            println!("[DEAD MAN SWITCH] fired for {}", user_id);
+            self.cancel_all(&MassCancel::all(user_id));
+        }
+    }
+
+    pub fn handle_amend(&mut self, mut amend: AmendOrder) {
+        amend.id = match self.resolve(amend.id, &amend.user_id, amend.by_client_id) {
+            Ok(id) => id,
+            Err(error) => return reply_error(amend.responder, error),
+        };
+        match self.book_of(&amend.id) {
+            Some(book) => {
+                book.handle_amend(amend);
+                book.publish_market_data();
+            }
+            None => reply_not_found(amend.responder, amend.id),
+        }
+    }
+
+    pub fn handle_trigger_level(&mut self, mut query: TriggerLevelQuery) {
+        query.id = match self.resolve(query.id, &query.user_id, query.by_client_id) {
+            Ok(id) => id,
+            Err(error) => return reply_error(query.responder, error),
+        };
+        match self.book_of(&query.id) {
+            Some(book) => book.handle_trigger_level(query),
+            None => reply_not_found(query.responder, query.id),
+        }
+    }
+
+    fn book_of(&mut self, id: &str) -> Option<&mut OrderBook> {
+        self.books.values_mut().find(|book| book.is_live(id))
+    }
+}
+
+fn reply_not_found<T>(responder: Option<oneshot::Sender<Result<T, BookError>>>, id: String) {
+    reply_error(responder, BookError::OrderNotFound(id));
+}
+
+fn reply_error<T>(responder: Option<oneshot::Sender<Result<T, BookError>>>, error: BookError) {
+    if let Some(responder) = responder {
+        let _ = responder.send(Err(error));
+    }
+}
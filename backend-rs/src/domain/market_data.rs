@@ -0,0 +1,153 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::domain::order::{Amount, DepthLevels, MarketPhase, Order, Price, Side};
+
+// Public market data the book thread publishes to websocket subscribers, tagged with the
+// channel a client subscribes to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "channel", rename_all = "lowercase")]
+pub enum MarketData {
+    Depth(DepthUpdate),
+    L3(L3Event),
+    Auction(AuctionUpdate),
+}
+
+impl MarketData {
+    pub fn channel(&self) -> &'static str {
+        match self {
+            MarketData::Depth(_) => "depth",
+            MarketData::L3(_) => "l3",
+            MarketData::Auction(_) => "auction",
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        match self {
+            MarketData::Depth(update) => &update.symbol,
+            MarketData::L3(event) => &event.symbol,
+            MarketData::Auction(update) => &update.symbol,
+        }
+    }
+}
+
+// Price levels that changed in one book event, with their new visible amount (0 means the level
+// is gone). Sequence numbers are per symbol and continue from the snapshot's.
+#[derive(Debug, Clone, Serialize)]
+pub struct DepthUpdate {
+    pub symbol: String,
+    pub sequence: u64,
+    pub bids: DepthLevels,
+    pub asks: DepthLevels,
+}
+
+#[derive(Serialize)]
+pub struct DepthSnapshot {
+    pub symbol: String,
+    // sequence of the last update already included, later updates apply on top
+    pub sequence: u64,
+    pub bids: DepthLevels,
+    pub asks: DepthLevels,
+    pub spread: Option<Decimal>,
+}
+
+// Sent when the market changes phase, and on every book change while an auction runs with the
+// price and amount the auction would uncross at if it ended now.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuctionUpdate {
+    pub symbol: String,
+    #[serde(flatten)]
+    pub phase: MarketPhase,
+    pub indicative_price: Option<Price>,
+    pub indicative_volume: Amount,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum L3EventKind {
+    // joined the back of its price level
+    Add,
+    // amount changed in place, queue position kept
+    Modify,
+    // left the book without trading
+    Cancel,
+    // traded as the resting side
+    Execute,
+}
+
+// One change to one resting order. `amount` is what is left visible in the book afterwards.
+// Owners are anonymized per book, the same user always maps to the same owner string.
+#[derive(Debug, Clone, Serialize)]
+pub struct L3Event {
+    pub symbol: String,
+    pub sequence: u64,
+    #[serde(rename = "type")]
+    pub kind: L3EventKind,
+    pub order_id: String,
+    pub owner: String,
+    pub side: Side,
+    pub price: Price,
+    pub amount: Amount,
+    // execute only
+    pub filled: Option<Amount>,
+}
+
+impl L3Event {
+    // Symbol, sequence and the anonymized owner are filled in by the book when it publishes,
+    // until then `owner` holds the real user id.
+    pub fn new(kind: L3EventKind, order: &Order) -> Self {
+        L3Event {
+            symbol: String::new(),
+            sequence: 0,
+            kind,
+            order_id: order.id.clone(),
+            owner: order.user_id.clone(),
+            side: order.side,
+            price: order.price,
+            amount: match kind {
+                L3EventKind::Cancel => Amount::ZERO,
+                _ => order.amount,
+            },
+            filled: None,
+        }
+    }
+
+    pub fn executed(order: &Order, filled: Amount) -> Self {
+        L3Event {
+            filled: Some(filled),
+            ..L3Event::new(L3EventKind::Execute, order)
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct L3Order {
+    pub order_id: String,
+    pub owner: String,
+    pub price: Price,
+    pub amount: Amount,
+}
+
+// Every resting order, best price first and in queue order within a level.
+#[derive(Serialize)]
+pub struct L3Snapshot {
+    pub symbol: String,
+    // sequence of the last l3 event already included
+    pub sequence: u64,
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
+}
+
+pub struct L3Query {
+    pub symbol: String,
+
+    pub responder: Option<oneshot::Sender<L3Snapshot>>,
+}
+
+pub struct DepthQuery {
+    pub symbol: String,
+    pub levels: usize,
+
+    pub responder: Option<oneshot::Sender<DepthSnapshot>>,
+}
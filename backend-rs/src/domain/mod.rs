@@ -1,6 +1,15 @@
+pub mod fee_tier;
+pub mod idempotency;
+pub mod instrument;
+pub mod market;
+pub mod market_data;
 pub mod oracle;
 pub mod order;
+pub mod order_history;
 pub mod position;
+pub mod trade_window;
+pub mod trigger;
+#[allow(dead_code, non_snake_case)]
 pub mod utils;
 pub mod wallet;
 
@@ -3,33 +3,43 @@ use rust_decimal::{prelude::FromPrimitive, Decimal};
 use rust_decimal_macros::dec;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Copy)]
-pub struct BtcPrice {
+use crate::domain::instrument::Instrument;
+
+#[derive(Debug, Clone)]
+pub struct IndexPrice {
+    pub symbol: String,
+    #[allow(dead_code)]
     pub timestamp: u64,
     pub price_usd: Decimal,
 }
 
 pub struct Oracle {
+    symbol: String,
     price: Decimal,
     rng: StdRng,
 }
 
 impl Oracle {
-    pub fn new(seed: Option<u64>) -> Self {
+    pub fn new(instrument: &Instrument, seed: Option<u64>) -> Self {
         let seed = seed.unwrap_or_else(|| {
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs()
         });
+        // oracles started together would otherwise move in lockstep
+        let seed = instrument.symbol.bytes().fold(seed, |seed, byte| {
+            seed.wrapping_mul(31).wrapping_add(byte as u64)
+        });
 
         Oracle {
-            price: dec!(60_000.0),
+            symbol: instrument.symbol.to_string(),
+            price: instrument.initial_price,
             rng: StdRng::seed_from_u64(seed),
         }
     }
 
-    pub fn next_price(&mut self) -> BtcPrice {
+    pub fn next_price(&mut self) -> IndexPrice {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -50,7 +60,11 @@ impl Oracle {
         let event = if event_chance < 0.001 {
             // 0.1% chance
             let magnitude = self.rng.random_range(-0.05..0.08); // -5% to +8%
-            println!("⚠️  Market Event! BTC moved by {:.2}%", magnitude * 100.0);
+            println!(
+                "⚠️  Market Event! {} moved by {:.2}%",
+                self.symbol,
+                magnitude * 100.0
+            );
             magnitude
         } else {
             0.0
@@ -61,11 +75,10 @@ impl Oracle {
         self.price *= dec!(1.0) + Decimal::from_f64(pct_change).unwrap();
         self.price = self.price.max(dec!(100.0)); // Never go below $100
 
-        let btc_price = BtcPrice {
+        IndexPrice {
+            symbol: self.symbol.clone(),
             timestamp: now,
             price_usd: self.price,
-        };
-
-        btc_price
+        }
     }
 }
@@ -1,29 +1,61 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fmt::{self};
+use std::hash::{BuildHasher, RandomState};
 use tokio::sync::mpsc::{self};
 
 use rust_decimal_macros::dec;
 use tokio::sync::oneshot;
 
 use rust_decimal::Decimal;
+use serde::Serialize;
 use uuid::Uuid;
-use OrderType::{LIMIT, MARKET};
-
-use crate::domain::position::{EngineEvent, Position, Trade};
-use crate::domain::wallet::{WalletDebitMessage, WalletEvent, WalletOneshotReply};
-
-#[derive(Debug, Clone, PartialEq)]
+use OrderType::{LIMIT, STOP_LIMIT, STOP_MARKET, TRAILING_STOP};
+
+use crate::domain::fee_tier::VolumeTiers;
+use crate::domain::instrument::{Instrument, MatchingAlgorithm};
+use crate::domain::market_data::{
+    AuctionUpdate, DepthSnapshot, DepthUpdate, L3Event, L3EventKind, L3Order, L3Snapshot,
+    MarketData,
+};
+use crate::domain::order_history::{OrderHistory, OrderRecord};
+use crate::domain::position::{BracketMessage, Brackets, EngineEvent, Position, Trade};
+use crate::domain::trade_window::TradeWindow;
+use crate::domain::trigger::TriggerBook;
+use crate::domain::utils::now_secs;
+use crate::domain::wallet::AvailableBalances;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[allow(non_camel_case_types)]
 pub enum OrderType {
     MARKET,
     LIMIT,
+    STOP_MARKET,
+    STOP_LIMIT,
+    TRAILING_STOP,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub enum Side {
     BID,
     ASK,
 }
 
+// How long the unfilled part of a limit order stays in the book.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeInForce {
+    // good till cancelled
+    GTC,
+    // immediate or cancel: fill what crosses now, cancel the rest
+    IOC,
+    // fill or kill: fill completely right now or not at all
+    FOK,
+    // good till date: rests until the unix timestamp (seconds) passes
+    GTD(u64),
+}
+
 impl fmt::Display for Side {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -35,15 +67,94 @@ impl fmt::Display for Side {
 
 pub type Amount = Decimal;
 pub type Price = Decimal;
+pub type DepthLevels = Vec<(Price, Amount)>;
+
+// What to do when an incoming order would trade against a resting order of the same user.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelfTradePrevention {
+    CancelTaker,
+    CancelMaker,
+    CancelBoth,
+}
+
+// How far a trailing stop's trigger stays behind the best mark price seen since it was placed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailingOffset {
+    Absolute(Price),
+    // in percent of the best mark, 1.5 means 1.5%
+    Percent(Decimal),
+}
+
+impl TrailingOffset {
+    // Trigger level for a trailing stop on `side` whose best mark so far is `reference`: below
+    // the highest mark for sells, above the lowest mark for buys.
+    pub fn trigger_from(self, side: Side, reference: Price) -> Price {
+        let distance = match self {
+            TrailingOffset::Absolute(offset) => offset,
+            TrailingOffset::Percent(percent) => reference * percent / dec!(100),
+        };
+        match side {
+            Side::BID => reference + distance,
+            Side::ASK => reference - distance,
+        }
+    }
+}
+
+impl fmt::Display for TrailingOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrailingOffset::Absolute(offset) => write!(f, "{}", offset),
+            TrailingOffset::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+// What to do with a post-only order that would take liquidity on arrival.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostOnly {
+    Reject,
+    // move it one tick inside the spread so it rests as a maker instead
+    Reprice,
+}
 
 pub struct Order {
     pub id: String,
     pub user_id: String,
+    // the client's own id for the order, unique per user among their open orders
+    pub client_order_id: Option<String>,
+    // a retried submission with the same key gets the first one's result back
+    pub idempotency_key: Option<String>,
+    pub symbol: String,
     pub order_type: OrderType,
     pub amount: Amount,
     pub price: Price,
     pub side: Side,
     pub leverage: Decimal,
+    pub time_in_force: TimeInForce,
+    pub post_only: Option<PostOnly>,
+    // iceberg orders only show this much in the book at a time, the rest waits in `reserve`
+    pub display_amount: Option<Amount>,
+    pub reserve: Amount,
+    // may only shrink the user's position, never grow or flip it
+    pub reduce_only: bool,
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // set while matching once self-trade prevention kicked in for this order
+    pub self_trade_prevented: bool,
+    // the client's price when post-only had to move the order inside the spread
+    pub repriced_from: Option<Price>,
+    // fees paid on this order's fills so far, negative when rebates outweigh them
+    pub fees: Amount,
+    // only set for stop orders, the mark price at which they enter the book. Trailing stops
+    // move it along with the mark, it stays None until they have seen their first mark price
+    pub trigger_price: Option<Price>,
+    pub trailing_offset: Option<TrailingOffset>,
+    // market orders: worst price they may trade at, given directly or worked out from
+    // `max_slippage_bps` off the best opposite price when the order reaches the book
+    pub protection_price: Option<Price>,
+    pub max_slippage_bps: Option<Decimal>,
+    // take-profit / stop-loss to attach to the position once this order fills
+    pub brackets: Option<Brackets>,
 
     pub responder: Option<oneshot::Sender<OrderResponse>>,
 }
@@ -53,8 +164,148 @@ impl Order {
         if self.amount <= dec!(0) {
             return Err(format!("amount must be > 0, got {}", self.amount));
         }
+        if matches!(self.order_type, STOP_MARKET | STOP_LIMIT) {
+            match self.trigger_price {
+                Some(trigger_price) if trigger_price > dec!(0) => {}
+                _ => return Err("stop orders need a trigger price > 0".to_string()),
+            }
+        }
+        match (&self.order_type, self.trailing_offset) {
+            (TRAILING_STOP, None) => {
+                return Err("trailing stops need a trailing offset or percent".to_string())
+            }
+            (TRAILING_STOP, Some(_)) if self.trigger_price.is_some() => {
+                return Err("trailing stops set their own trigger price".to_string())
+            }
+            (TRAILING_STOP, Some(TrailingOffset::Absolute(offset))) if offset <= dec!(0) => {
+                return Err(format!("trailing offset must be > 0, got {}", offset))
+            }
+            (TRAILING_STOP, Some(TrailingOffset::Percent(percent)))
+                if percent <= dec!(0) || percent >= dec!(100) =>
+            {
+                return Err(format!(
+                    "trailing percent must be between 0 and 100, got {}",
+                    percent
+                ))
+            }
+            (TRAILING_STOP, Some(_)) | (_, None) => {}
+            (_, Some(_)) => return Err("only trailing stops take a trailing offset".to_string()),
+        }
+        if self.protection_price.is_some() || self.max_slippage_bps.is_some() {
+            if !matches!(
+                self.order_type,
+                OrderType::MARKET | STOP_MARKET | TRAILING_STOP
+            ) {
+                return Err("slippage protection is only available on market orders".to_string());
+            }
+            for (name, value) in [
+                ("protection price", self.protection_price),
+                ("max slippage", self.max_slippage_bps),
+            ] {
+                if let Some(value) = value.filter(|value| *value <= dec!(0)) {
+                    return Err(format!("{} must be > 0, got {}", name, value));
+                }
+            }
+            if let Some(bps) = self.max_slippage_bps.filter(|bps| *bps > dec!(10_000)) {
+                return Err(format!(
+                    "max slippage must be at most 10000 bps, got {}",
+                    bps
+                ));
+            }
+        }
+        if matches!(self.order_type, LIMIT | STOP_LIMIT) && self.price <= dec!(0) {
+            return Err(format!("limit price must be > 0, got {}", self.price));
+        }
+        if let Some(brackets) = &self.brackets {
+            brackets.validate()?;
+        }
+        for (name, value) in [
+            ("client order id", &self.client_order_id),
+            ("idempotency key", &self.idempotency_key),
+        ] {
+            if let Some(value) = value
+                .as_ref()
+                .filter(|value| !(1..=64).contains(&value.len()))
+            {
+                return Err(format!(
+                    "{} must be 1 to 64 characters, got {:?}",
+                    name, value
+                ));
+            }
+        }
+        if let Some(display_amount) = self.display_amount {
+            if !matches!(self.order_type, LIMIT | STOP_LIMIT) {
+                return Err("iceberg is only available on limit orders".to_string());
+            }
+            if display_amount <= dec!(0) {
+                return Err(format!(
+                    "display amount must be > 0, got {}",
+                    display_amount
+                ));
+            }
+        }
+        if self.post_only.is_some() {
+            if !matches!(self.order_type, LIMIT | STOP_LIMIT) {
+                return Err("post only is only available on limit orders".to_string());
+            }
+            if !matches!(self.time_in_force, TimeInForce::GTC | TimeInForce::GTD(_)) {
+                return Err("post only orders must be gtc or gtd".to_string());
+            }
+        }
+        if let TimeInForce::GTD(expires_at) = self.time_in_force {
+            if expires_at <= now_secs() {
+                return Err(format!("expiry {} is already in the past", expires_at));
+            }
+        }
         Ok(())
     }
+
+    // Visible plus hidden amount still to be filled.
+    pub fn remaining(&self) -> Amount {
+        self.amount + self.reserve
+    }
+
+    // Moves the next slice of an iceberg's hidden reserve into its visible amount.
+    pub fn refill(&mut self) {
+        let slice = self
+            .display_amount
+            .unwrap_or(self.reserve)
+            .min(self.reserve);
+        self.amount += slice;
+        self.reserve -= slice;
+    }
+
+    // Cuts the order down to `total` remaining, taking from the hidden reserve first so the
+    // visible part (and its queue position) is touched last.
+    pub fn shrink_to(&mut self, total: Amount) {
+        let mut cut = (self.remaining() - total).max(dec!(0));
+        let from_reserve = cut.min(self.reserve);
+        self.reserve -= from_reserve;
+        cut -= from_reserve;
+        self.amount -= cut.min(self.amount);
+    }
+
+    // Liquidations and take-profit/stop-loss closes: the reduce-only market orders the position
+    // tracker builds with `Order::from(&Position)`, nobody is waiting on a response for them.
+    pub fn is_position_close(&self) -> bool {
+        self.reduce_only && self.order_type == OrderType::MARKET && self.responder.is_none()
+    }
+
+    // Whether self-trade prevention cancelled what was left of this (incoming) order.
+    pub fn cancelled_by_self_trade(&self) -> bool {
+        self.self_trade_prevented
+            && self.self_trade_prevention != Some(SelfTradePrevention::CancelMaker)
+    }
+
+    // Whether this order is willing to trade at a resting price on the other side.
+    pub fn crosses(&self, price: Price) -> bool {
+        match (&self.order_type, self.side) {
+            (LIMIT, Side::BID) => price <= self.price,
+            (LIMIT, Side::ASK) => price >= self.price,
+            (_, Side::BID) => self.protection_price.is_none_or(|limit| price <= limit),
+            (_, Side::ASK) => self.protection_price.is_none_or(|limit| price >= limit),
+        }
+    }
 }
 
 impl From<&Position> for Order {
@@ -65,49 +316,403 @@ impl From<&Position> for Order {
         Order {
             id: Uuid::new_v4().to_string(),
             user_id: p.user_id.clone(),
+            client_order_id: None,
+            idempotency_key: None,
+            symbol: p.symbol.clone(),
             amount: size.abs(), // POSITIVE
             price: dec!(0),
             order_type: OrderType::MARKET,
             side,
             leverage: dec!(1),
+            time_in_force: TimeInForce::IOC,
+            post_only: None,
+            display_amount: None,
+            reserve: dec!(0),
+            reduce_only: true,
+            self_trade_prevention: None,
+            self_trade_prevented: false,
+            repriced_from: None,
+            fees: dec!(0),
+            trigger_price: None,
+            trailing_offset: None,
+            protection_price: None,
+            max_slippage_bps: None,
+            brackets: None,
+            responder: None,
+        }
+    }
+}
+
+// Cancels, amends and trigger queries name their order either by its id or, with
+// `by_client_id`, by the client order id it was placed with.
+pub struct CancelOrder {
+    pub id: String,
+    pub user_id: String,
+    pub by_client_id: bool,
+
+    pub responder: Option<oneshot::Sender<Result<Amount, BookError>>>,
+}
+
+pub struct AmendOrder {
+    pub id: String,
+    pub user_id: String,
+    pub by_client_id: bool,
+    pub amount: Option<Amount>,
+    pub price: Option<Price>,
+
+    pub responder: Option<oneshot::Sender<Result<OrderResponse, BookError>>>,
+}
+
+pub struct TriggerLevelQuery {
+    pub id: String,
+    pub user_id: String,
+    pub by_client_id: bool,
+
+    pub responder: Option<oneshot::Sender<Result<Option<Price>, BookError>>>,
+}
+
+// Cancels every open order of a user, resting and stop orders alike. Each filter left empty
+// matches everything, the price range is checked against the orders' limit price.
+pub struct MassCancel {
+    pub user_id: String,
+    pub symbol: Option<String>,
+    pub side: Option<Side>,
+    pub min_price: Option<Price>,
+    pub max_price: Option<Price>,
+
+    // ids of the cancelled orders
+    pub responder: Option<oneshot::Sender<Vec<String>>>,
+}
+
+impl MassCancel {
+    pub fn all(user_id: String) -> Self {
+        MassCancel {
+            user_id,
+            symbol: None,
+            side: None,
+            min_price: None,
+            max_price: None,
             responder: None,
         }
     }
 }
 
+// Arms, refreshes or (with a timeout of 0) disarms a user's dead man's switch: unless it is
+// refreshed within `timeout` seconds, all of the user's orders are cancelled.
+pub struct DeadManSwitch {
+    pub user_id: String,
+    pub timeout: u64,
+
+    // unix seconds the switch now fires at, None once disarmed
+    pub responder: Option<oneshot::Sender<Option<u64>>>,
+}
+
+// Trading state of one instrument's book, as published on /instruments/{symbol}/status.
+#[derive(Serialize)]
+pub struct MarketStatus {
+    pub symbol: String,
+    #[serde(flatten)]
+    pub phase: MarketPhase,
+    // what an auction would uncross at right now, only while one runs
+    pub indicative_price: Option<Price>,
+    pub indicative_volume: Amount,
+    pub index_price: Option<Price>,
+    pub mark_price: Option<Price>,
+    pub last_trade_price: Option<Price>,
+    pub best_bid: Option<Price>,
+    pub best_ask: Option<Price>,
+}
+
+pub struct MarketStatusQuery {
+    pub symbol: String,
+
+    pub responder: Option<oneshot::Sender<MarketStatus>>,
+}
+
+// Halts an instrument for `duration` seconds, it re-opens with an auction afterwards.
+pub struct MaintenanceQuery {
+    pub symbol: String,
+    pub duration: u64,
+
+    pub responder: Option<oneshot::Sender<MarketStatus>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseReason {
+    Open,
+    CircuitBreaker,
+    Maintenance,
+}
+
+// Where a book is in its trading day. Every market starts in its opening auction, and every
+// halt is followed by a re-opening auction before matching continues.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum MarketPhase {
+    Continuous,
+    // limit orders rest without matching, then everything that crosses trades at one price
+    Auction {
+        reason: PhaseReason,
+        uncross_at: u64,
+    },
+    // no new orders or amends are accepted, resting orders stay where they are
+    Halted {
+        reason: PhaseReason,
+        until: u64,
+    },
+}
+
+#[derive(Debug)]
+pub enum BookError {
+    OrderNotFound(String),
+    NotOrderOwner(String),
+    InvalidAmend(String),
+    InsufficientBalance,
+    WouldTakeLiquidity(String),
+    // unix seconds the halt ends at
+    MarketHalted(u64),
+}
+
+impl fmt::Display for BookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BookError::OrderNotFound(id) => write!(f, "order {} not found", id),
+            BookError::NotOrderOwner(id) => write!(f, "order {} belongs to another user", id),
+            BookError::InvalidAmend(reason) => write!(f, "invalid amend: {}", reason),
+            BookError::InsufficientBalance => write!(f, "insufficient balance"),
+            BookError::WouldTakeLiquidity(id) => {
+                write!(f, "post only order {} would take liquidity", id)
+            }
+            BookError::MarketHalted(until) => {
+                write!(f, "trading halted until {}", until)
+            }
+        }
+    }
+}
+
 pub struct OrderBook {
+    pub instrument: &'static Instrument,
     pub bids: BTreeMap<Price, VecDeque<Order>>,
     pub asks: BTreeMap<Price, VecDeque<Order>>,
     pub best_bid: Option<Price>,
     pub best_ask: Option<Price>,
 
+    // resting order id -> (side, price level), so an order can be found without walking the book
+    orders: HashMap<String, (Side, Price)>,
+    triggers: TriggerBook,
+    // expiry (unix seconds) -> ids of good-till-date orders resting until then
+    expiries: BTreeMap<u64, Vec<String>>,
+    // net position per user (long > 0, short < 0) built from the trades this book produces, the
+    // same ones the position tracker applies, so reduce-only checks don't need to ask it
+    net_positions: HashMap<String, Amount>,
+    // latest prices from the position tracker, the index is what the price band is measured from
+    index_price: Option<Price>,
+    mark_price: Option<Price>,
+    last_trade_price: Option<Price>,
+    // the trades inside the circuit breaker window
+    recent_trades: TradeWindow,
+    phase: MarketPhase,
+    // set when the phase moved, so the next publish tells subscribers
+    phase_changed: bool,
+    // the single price every trade goes off at while an auction uncrosses
+    uncross_price: Option<Price>,
+    // one-cancels-other: order id -> id of the order it is linked with, kept in both directions
+    oco_links: HashMap<String, String>,
+    // linked orders that traded since the last settle_oco, their siblings are due for cancelling
+    oco_filled: Vec<String>,
+    // price levels changed since the last depth update went out
+    dirty_bids: BTreeSet<Price>,
+    dirty_asks: BTreeSet<Price>,
+    depth_sequence: u64,
+    // order level changes waiting to go out on the l3 feed
+    l3_events: Vec<L3Event>,
+    l3_sequence: u64,
+    // seeded per book at startup, turns user ids into owners that can't be traced back
+    owner_hasher: RandomState,
+    // state, fills and average price of the orders in this book and the ones that recently left
+    history: OrderHistory,
+    // position closes that came in while the market was halted or in an auction, at most one per
+    // user, sent in once continuous trading resumes
+    held_closes: Vec<Order>,
+
     position_tx: mpsc::UnboundedSender<EngineEvent>,
-    wallet_tx: mpsc::UnboundedSender<WalletEvent>,
+    balances: AvailableBalances,
+    tiers: VolumeTiers,
+    market_data_tx: mpsc::UnboundedSender<MarketData>,
 }
 
 #[derive(Clone)]
 pub struct OrderResponse {
+    pub order_id: String,
     pub status: String,
     pub filled: Amount,
     pub remaining: Amount,
+    pub fee: Amount,
 }
 impl fmt::Display for Order {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.order_type {
             OrderType::LIMIT => write!(
                 f,
-                "{} {} {} BTC @ {}",
-                self.user_id, self.side, self.amount, self.price
+                "{} {} {} {} @ {}",
+                self.user_id, self.side, self.amount, self.symbol, self.price
             ),
             OrderType::MARKET => write!(
                 f,
-                "{} {} {} BTC @ MARKET",
-                self.user_id, self.side, self.amount
+                "{} {} {} {} @ MARKET",
+                self.user_id, self.side, self.amount, self.symbol
+            ),
+            OrderType::STOP_MARKET => write!(
+                f,
+                "{} {} {} {} @ MARKET if mark hits {}",
+                self.user_id,
+                self.side,
+                self.amount,
+                self.symbol,
+                self.trigger_price.unwrap_or_default()
+            ),
+            OrderType::STOP_LIMIT => write!(
+                f,
+                "{} {} {} {} @ {} if mark hits {}",
+                self.user_id,
+                self.side,
+                self.amount,
+                self.symbol,
+                self.price,
+                self.trigger_price.unwrap_or_default()
+            ),
+            OrderType::TRAILING_STOP => write!(
+                f,
+                "{} {} {} {} @ MARKET trailing mark by {}",
+                self.user_id,
+                self.side,
+                self.amount,
+                self.symbol,
+                self.trailing_offset
+                    .map(|offset| offset.to_string())
+                    .unwrap_or_default()
             ),
         }
     }
 }
 
+pub(crate) fn reject(mut order: Order, status: String) {
+    if let Some(responder) = order.responder.take() {
+        let _ = responder.send(OrderResponse {
+            order_id: order.id.clone(),
+            status,
+            filled: dec!(0),
+            remaining: order.amount,
+            fee: dec!(0),
+        });
+    }
+}
+
+fn record_trade(
+    net_positions: &mut HashMap<String, Amount>,
+    long_id: &str,
+    short_id: &str,
+    amount: Amount,
+) {
+    for (user_id, change) in [(long_id, amount), (short_id, -amount)] {
+        let position = net_positions.entry(user_id.to_string()).or_default();
+        *position += change;
+        if position.is_zero() {
+            net_positions.remove(user_id);
+        }
+    }
+}
+
+// How much a `side` order from `user_id` can trade before the position would flip.
+fn reducible_amount(net_positions: &HashMap<String, Amount>, user_id: &str, side: Side) -> Amount {
+    let position = net_positions.get(user_id).copied().unwrap_or_default();
+    match side {
+        Side::BID => (-position).max(dec!(0)),
+        Side::ASK => position.max(dec!(0)),
+    }
+}
+
+// Splits `amount` over the orders resting at one price level by the instrument's matching
+// algorithm. Entry i is what the i-th order in the queue trades, the entries add up to `amount`
+// or to everything visible at the level. Pro-rata shares are rounded down to whole lots and the
+// lots left over by rounding go out in time priority.
+fn allocate(instrument: &Instrument, queue: &VecDeque<Order>, amount: Amount) -> VecDeque<Amount> {
+    let mut allocations: VecDeque<Amount> = queue.iter().map(|_| dec!(0)).collect();
+    let mut left = amount;
+
+    let pro_rata_from = match instrument.matching {
+        MatchingAlgorithm::Fifo => None,
+        MatchingAlgorithm::ProRata => Some(0),
+        MatchingAlgorithm::TopOrderProRata { top_order_percent } => {
+            if let (Some(top), Some(order)) = (allocations.front_mut(), queue.front()) {
+                *top = instrument
+                    .round_to_lot(amount * top_order_percent / dec!(100))
+                    .min(order.amount);
+                left -= *top;
+            }
+            Some(1)
+        }
+    };
+
+    if let Some(from) = pro_rata_from {
+        let sharing = queue.iter().skip(from);
+        let total: Amount = sharing.clone().map(|order| order.amount).sum();
+        if total > dec!(0) {
+            let shared = left.min(total);
+            for (allocation, order) in allocations.iter_mut().skip(from).zip(sharing) {
+                let share = instrument.round_to_lot(shared * order.amount / total);
+                *allocation += share;
+                left -= share;
+            }
+        }
+    }
+
+    for (allocation, order) in allocations.iter_mut().zip(queue) {
+        let extra = (order.amount - *allocation).min(left);
+        *allocation += extra;
+        left -= extra;
+    }
+
+    allocations
+}
+
+// Shrinks a resting reduce-only order to what its owner's position still allows, releasing the
+// funds held for the cut part. Returns the order's new amount.
+fn trim_reduce_only(
+    net_positions: &HashMap<String, Amount>,
+    balances: &AvailableBalances,
+    order: &mut Order,
+) -> Amount {
+    let reducible = reducible_amount(net_positions, &order.user_id, order.side);
+    if order.remaining() > reducible {
+        balances.release(
+            &order.user_id,
+            (order.remaining() - reducible) * order.price,
+        );
+        order.shrink_to(reducible);
+    }
+    order.remaining()
+}
+
+// Brackets go out on the same channel right after the order's first trade, so the position they
+// belong to already exists by the time the position tracker sees them.
+fn attach_brackets(
+    position_tx: &mpsc::UnboundedSender<EngineEvent>,
+    user_id: &str,
+    symbol: &str,
+    brackets: Brackets,
+) {
+    if let Err(err) = position_tx.send(EngineEvent::SetBrackets(BracketMessage {
+        user_id: user_id.to_string(),
+        symbol: symbol.to_string(),
+        brackets,
+        responder: None,
+    })) {
+        eprintln!("[POSITION SENDER ERROR] {}", err);
+    }
+}
+
 impl fmt::Display for OrderBook {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.bids.is_empty() && self.asks.is_empty() {
@@ -124,7 +729,7 @@ impl fmt::Display for OrderBook {
                 for order in orders {
                     output.push_str(&format!("{}\n", order));
                 }
-                output.push_str("\n");
+                output.push('\n');
             }
         }
 
@@ -136,7 +741,7 @@ impl fmt::Display for OrderBook {
                 for order in orders.iter().rev() {
                     output.push_str(&format!("{}\n", order));
                 }
-                output.push_str("\n");
+                output.push('\n');
             }
         }
 
@@ -146,291 +751,1098 @@ impl fmt::Display for OrderBook {
 
 impl OrderBook {
     pub fn new(
+        instrument: &'static Instrument,
         position_tx: mpsc::UnboundedSender<EngineEvent>,
-        wallet_tx: mpsc::UnboundedSender<WalletEvent>,
+        balances: AvailableBalances,
+        tiers: VolumeTiers,
+        market_data_tx: mpsc::UnboundedSender<MarketData>,
     ) -> Self {
         OrderBook {
+            instrument,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             best_bid: None,
             best_ask: None,
+            orders: HashMap::new(),
+            triggers: TriggerBook::new(),
+            expiries: BTreeMap::new(),
+            net_positions: HashMap::new(),
+            index_price: None,
+            mark_price: None,
+            last_trade_price: None,
+            recent_trades: TradeWindow::default(),
+            phase: MarketPhase::Auction {
+                reason: PhaseReason::Open,
+                uncross_at: now_secs() + instrument.auction_duration,
+            },
+            phase_changed: true,
+            uncross_price: None,
+            oco_links: HashMap::new(),
+            oco_filled: Vec::new(),
+            dirty_bids: BTreeSet::new(),
+            dirty_asks: BTreeSet::new(),
+            depth_sequence: 0,
+            l3_events: Vec::new(),
+            l3_sequence: 0,
+            owner_hasher: RandomState::new(),
+            history: OrderHistory::default(),
+            held_closes: Vec::new(),
             position_tx,
-            wallet_tx,
+            balances,
+            tiers,
+            market_data_tx,
         }
     }
 
-    pub async fn insert_order(&mut self, order: Order) {
-        match order.side {
-            Side::BID => self.handle_buy(order).await,
-            Side::ASK => self.handle_sell(order).await,
+    // Every order the book sees comes through here, its history record is opened on the way in
+    // and finished right away unless the order ended up resting or waiting for its trigger.
+    pub fn insert_order(&mut self, order: Order) {
+        if order.is_position_close() && self.phase != MarketPhase::Continuous {
+            self.hold_close(order);
+            return;
         }
 
-        self.update_best_prices();
+        let id = order.id.clone();
+        self.history.open(&order, now_secs());
+        self.place_order(order);
+        if !self.is_live(&id) {
+            self.history.finish(&id, now_secs());
+        }
     }
 
-    pub async fn handle_buy(&mut self, mut order: Order) {
-        let (oneshot_tx, oneshot_rx) = oneshot::channel::<WalletOneshotReply>();
-
-        let sent = self.wallet_tx.send(WalletEvent::Debit(WalletDebitMessage {
-            wallet_id: order.user_id.clone(),
-            amount: order.amount * order.price,
-
-            oneshot_reply: Some(oneshot_tx),
-        }));
+    // A close can't be rejected the way a user's order can, nobody would place it again, so it
+    // waits for the market to reopen. Being a market order it can't join an auction either. A newer close for the same user replaces the held one, it
+    // was built from the latest position size.
+    fn hold_close(&mut self, order: Order) {
+        println!("[CLOSE HELD] {} until trading resumes", order);
+        match self
+            .held_closes
+            .iter_mut()
+            .find(|held| held.user_id == order.user_id)
+        {
+            Some(held) => *held = order,
+            None => self.held_closes.push(order),
+        }
+    }
 
-        if let Err(err) = sent {
-            eprintln!("[ORDER WALLET CHECK ERROR] {}", err);
+    fn place_order(&mut self, mut order: Order) {
+        if matches!(order.order_type, STOP_MARKET | STOP_LIMIT | TRAILING_STOP) {
+            self.insert_stop(order);
+            return;
         }
 
-        match oneshot_rx.await {
-            Ok(msg) => {
-                if !msg.success {
-                    if let Some(responder) = order.responder {
-                        if let Err(_) = responder.send(OrderResponse {
-                            status: "order could not be made, insufficient balance".to_string(),
-                            filled: dec!(0),
-                            remaining: dec!(0),
-                        }) {
-                            eprintln!(
-                                "[ORDER WALLET CHECK RESPONSE ERROR] cannot send error message back"
-                            );
-                        }
-                    }
-                    return;
-                }
+        match self.phase {
+            MarketPhase::Halted { until, .. } => {
+                reject(order, BookError::MarketHalted(until).to_string());
+                return;
             }
-            Err(_) => {
-                eprintln!("[ORDER WALLET CHECK ERROR] wallet task dropped oneshot sender");
+            // nothing trades until the uncross, so only orders that can wait for it go in
+            MarketPhase::Auction { uncross_at, .. }
+                if order.order_type != LIMIT
+                    || !matches!(order.time_in_force, TimeInForce::GTC | TimeInForce::GTD(_)) =>
+            {
+                reject(
+                    order,
+                    format!(
+                        "rejected, only resting limit orders are accepted during the auction until {}",
+                        uncross_at
+                    ),
+                );
                 return;
             }
+            _ => {}
         }
-
-        let mut filled: Amount = dec!(0);
-
-        let mut prices_to_remove: Vec<Price> = Vec::new();
-
-        // ascending price order
-        for (&price, queue) in self.asks.iter_mut() {
-            if order.order_type == LIMIT && price > order.price {
-                match self.bids.get_mut(&order.price) {
-                    Some(buys) => {
-                        if let Some(responder) = order.responder.take() {
-                            let _ = responder.send(OrderResponse {
-                                status: "order partially filled, remaining added to queue!"
-                                    .to_string(),
-                                filled,
-                                remaining: order.amount,
-                            });
-                        }
-                        buys.push_back(order);
+        if order.order_type == LIMIT {
+            if let Err(reason) = self.check_price_band(order.price) {
+                reject(order, format!("price band rejected, {}", reason));
+                return;
+            }
+        }
+        if let Some(max_slippage_bps) = order.max_slippage_bps {
+            let slippage = max_slippage_bps / dec!(10_000);
+            match (order.side, self.best_ask, self.best_bid) {
+                (Side::BID, Some(best_ask), _) => {
+                    let Some(bound) = best_ask.checked_mul(dec!(1) + slippage) else {
+                        reject(
+                            order,
+                            "slippage protection rejected, bound overflows".to_string(),
+                        );
                         return;
-                    }
-                    None => {
-                        let mut new_queue: VecDeque<Order> = VecDeque::new();
-
-                        if let Some(responder_tx) = order.responder.take() {
-                            if let Err(_) = responder_tx.send(OrderResponse {
-                                status: "could not match, added to queue!".to_string(),
-                                filled: dec!(0),
-                                remaining: order.amount,
-                            }) {
-                                eprintln!("some shit happened to oneshot responder, can't send...");
-                            }
-                        }
-
-                        new_queue.push_back(order);
-                        self.bids.insert(price, new_queue);
+                    };
+                    order.protection_price = Some(
+                        order
+                            .protection_price
+                            .map_or(bound, |limit| limit.min(bound)),
+                    );
+                }
+                (Side::ASK, _, Some(best_bid)) => {
+                    let Some(bound) = best_bid.checked_mul(dec!(1) - slippage) else {
+                        reject(
+                            order,
+                            "slippage protection rejected, bound overflows".to_string(),
+                        );
                         return;
-                    }
+                    };
+                    order.protection_price = Some(
+                        order
+                            .protection_price
+                            .map_or(bound, |limit| limit.max(bound)),
+                    );
                 }
+                // nothing on the other side, so nothing to slip into either
+                _ => {}
             }
+        }
 
-            while let Some(ask) = queue.front_mut() {
-                let trade_amount = order.amount.min(ask.amount);
-                println!(
-                    "Matched BUY {} with SELL {} @ {} for {}",
-                    order.user_id, ask.user_id, price, trade_amount
-                );
-
-                order.amount -= trade_amount;
-                ask.amount -= trade_amount;
-                filled += trade_amount;
-
-                //  TODO: try_send does not give enough fucks to try again if the buffer is full
-                //        it will simply throw an error, catch it and either drop the trade,
-                //        see why tf is is blocked as it shouldn't as it has 10k limit or try
-                //        again.
-
-                // let the position tracker know the trade just happened here
-                if let Err(err) = self.position_tx.send(EngineEvent::Trade(Trade {
-                    long_id: order.user_id.clone(),
-                    short_id: ask.user_id.clone(),
-                    long_leverage: order.leverage,
-                    short_leverage: ask.leverage,
-                    amount: trade_amount,
-                    price,
-                })) {
-                    eprintln!("{}", err);
+        if order.reduce_only {
+            let reducible = reducible_amount(&self.net_positions, &order.user_id, order.side);
+            if reducible == dec!(0) {
+                if let Some(responder) = order.responder.take() {
+                    let _ = responder.send(OrderResponse {
+                        order_id: order.id.clone(),
+                        status: "reduce only rejected, no position to reduce".to_string(),
+                        filled: dec!(0),
+                        remaining: order.amount,
+                        fee: dec!(0),
+                    });
                 }
+                return;
+            }
+            order.amount = order.amount.min(reducible);
+        }
 
-                if ask.amount == dec!(0) {
-                    queue.pop_front();
+        if let Some(post_only) = order.post_only {
+            if self.would_take(order.side, order.price) {
+                let repriced = match (post_only, order.side, self.best_ask, self.best_bid) {
+                    (PostOnly::Reprice, Side::BID, Some(best_ask), _) => {
+                        best_ask - self.instrument.tick_size
+                    }
+                    (PostOnly::Reprice, Side::ASK, _, Some(best_bid)) => {
+                        best_bid + self.instrument.tick_size
+                    }
+                    _ => dec!(0),
+                };
+
+                if repriced <= dec!(0) {
+                    if let Some(responder) = order.responder.take() {
+                        let _ = responder.send(OrderResponse {
+                            order_id: order.id.clone(),
+                            status: "post only rejected, order would take liquidity".to_string(),
+                            filled: dec!(0),
+                            remaining: order.amount,
+                            fee: dec!(0),
+                        });
+                    }
+                    return;
                 }
-                if order.amount == dec!(0) {
-                    break;
+                // the new price is the other side's best plus a tick, which can sit outside the
+                // band the original price was checked against
+                if let Err(reason) = self.check_price_band(repriced) {
+                    reject(order, format!("post only rejected, repriced {}", reason));
+                    return;
                 }
-            }
 
-            if queue.is_empty() {
-                prices_to_remove.push(price);
+                order.repriced_from = Some(order.price);
+                order.price = repriced;
             }
+        }
 
-            if order.amount == dec!(0) {
-                break;
+        // fill or kill is checked before any funds are touched, so a kill leaves no trace
+        if order.time_in_force == TimeInForce::FOK {
+            if self.meets_own_order(&order) {
+                reject(
+                    order,
+                    "fill or kill rejected, would trade against an own order".to_string(),
+                );
+                return;
+            }
+            if self.available_liquidity(&order) < order.amount {
+                reject(
+                    order,
+                    "fill or kill rejected, not enough liquidity".to_string(),
+                );
+                return;
             }
         }
 
-        for price in prices_to_remove {
-            self.asks.remove(&price);
+        match order.side {
+            Side::BID => self.handle_buy(order),
+            Side::ASK => self.handle_sell(order),
         }
 
-        let dynamic_status = |type_: OrderType| match type_ {
-            MARKET => "disreguarding remaining amount.".to_string(),
-            LIMIT => "adding remaining in queue.".to_string(),
+        self.settle_oco();
+        self.check_circuit_breaker();
+        self.update_best_prices();
+    }
+
+    // Limit orders must be priced within the instrument's band around the latest index price.
+    fn check_price_band(&self, price: Price) -> Result<(), String> {
+        let Some(index_price) = self.index_price else {
+            return Ok(());
         };
 
-        if order.amount > dec!(0) {
-            if let Some(responder) = order.responder.take() {
-                let _ = responder.send(OrderResponse {
-                    status: dynamic_status(order.order_type.clone()),
-                    filled,
-                    remaining: order.amount,
+        let band = index_price * self.instrument.price_band / dec!(100);
+        if (price - index_price).abs() > band {
+            return Err(format!(
+                "{} is more than {}% away from the index price {}",
+                price,
+                self.instrument.price_band,
+                index_price.round_dp(2)
+            ));
+        }
+        Ok(())
+    }
+
+    // Halts matching when the last trade moved more than the instrument allows compared to any
+    // trade still inside the window. Checked after every match, so the match that trips the
+    // breaker still completes.
+    fn check_circuit_breaker(&mut self) {
+        let now = now_secs();
+        self.recent_trades
+            .prune(now.saturating_sub(self.instrument.circuit_breaker_window));
+
+        let (Some(last_price), Some((low, high))) =
+            (self.recent_trades.last(), self.recent_trades.range())
+        else {
+            return;
+        };
+        self.last_trade_price = Some(last_price);
+        // the move is measured against the earlier trade, so the lowest and highest price in the
+        // window are the ones furthest from the last trade
+        let max_move = self.instrument.circuit_breaker_move / dec!(100);
+        let tripped = [low, high]
+            .into_iter()
+            .any(|price| ((last_price - price) / price).abs() > max_move);
+
+        if tripped {
+            let halted_until = now + self.instrument.circuit_breaker_cool_off;
+            println!(
+                "[CIRCUIT BREAKER] {} halted until {}, last trade {}",
+                self.instrument.symbol, halted_until, last_price
+            );
+            self.set_phase(MarketPhase::Halted {
+                reason: PhaseReason::CircuitBreaker,
+                until: halted_until,
+            });
+            self.recent_trades.clear();
+        }
+    }
+
+    pub fn start_maintenance(&mut self, now: u64, duration: u64) {
+        println!(
+            "[MAINTENANCE] {} halted until {}",
+            self.instrument.symbol,
+            now.saturating_add(duration)
+        );
+        self.set_phase(MarketPhase::Halted {
+            reason: PhaseReason::Maintenance,
+            until: now.saturating_add(duration),
+        });
+    }
+
+    // Moves a finished halt on to its re-opening auction and uncrosses a finished auction,
+    // called from the book thread's timer.
+    pub fn advance_phase(&mut self, now: u64) {
+        match self.phase {
+            MarketPhase::Halted { reason, until } if now >= until => {
+                println!(
+                    "[AUCTION] {} re-opening after {:?}",
+                    self.instrument.symbol, reason
+                );
+                self.set_phase(MarketPhase::Auction {
+                    reason,
+                    uncross_at: now + self.instrument.auction_duration,
                 });
             }
-            if order.order_type == LIMIT {
-                self.bids.entry(order.price).or_default().push_back(order);
-            }
-        } else {
-            if let Some(responder) = order.responder.take() {
-                let _ = responder.send(OrderResponse {
-                    status: "order completely filled".to_string(),
-                    filled,
-                    remaining: dec!(0),
-                });
+            MarketPhase::Auction { uncross_at, .. } if now >= uncross_at => {
+                self.uncross();
+                self.set_phase(MarketPhase::Continuous);
+                self.settle_oco();
+                self.check_circuit_breaker();
+                self.update_best_prices();
+                // a close that trips the breaker again holds the rest until the next reopening
+                for order in std::mem::take(&mut self.held_closes) {
+                    self.insert_order(order);
+                }
             }
+            _ => {}
         }
     }
 
-    pub async fn handle_sell(&mut self, mut order: Order) {
-        let (oneshot_tx, oneshot_rx) = oneshot::channel::<WalletOneshotReply>();
+    fn set_phase(&mut self, phase: MarketPhase) {
+        self.phase = phase;
+        self.phase_changed = true;
+    }
 
-        if let Err(err) = self.wallet_tx.send(WalletEvent::Debit(WalletDebitMessage {
-            wallet_id: order.user_id.clone(),
-            amount: order.amount * order.price,
+    // The price an auction would uncross at and the amount that would trade there: the price
+    // with the most executable volume, then the smallest imbalance left over, then the one
+    // closest to the index price. Iceberg reserve counts only with `with_reserve`, what gets
+    // published leaves it out.
+    fn equilibrium(&self, with_reserve: bool) -> Option<(Price, Amount)> {
+        let resting = |queue: &VecDeque<Order>| -> Amount {
+            queue
+                .iter()
+                .map(|order| {
+                    if with_reserve {
+                        order.remaining()
+                    } else {
+                        order.amount
+                    }
+                })
+                .sum()
+        };
+        let reference = self.index_price.or(self.last_trade_price);
+        let distance = |price: Price| reference.map(|reference| (price - reference).abs());
+
+        self.bids
+            .keys()
+            .chain(self.asks.keys())
+            .copied()
+            .collect::<BTreeSet<Price>>()
+            .into_iter()
+            .filter_map(|price| {
+                let demand: Amount = self
+                    .bids
+                    .range(price..)
+                    .map(|(_, queue)| resting(queue))
+                    .sum();
+                let supply: Amount = self
+                    .asks
+                    .range(..=price)
+                    .map(|(_, queue)| resting(queue))
+                    .sum();
+                let volume = demand.min(supply);
+                (volume > dec!(0)).then_some((price, volume, (demand - supply).abs()))
+            })
+            .max_by(|a, b| {
+                a.1.cmp(&b.1)
+                    .then(b.2.cmp(&a.2))
+                    .then(distance(b.0).cmp(&distance(a.0)))
+            })
+            .map(|(price, volume, _)| (price, volume))
+    }
 
-            oneshot_reply: Some(oneshot_tx),
-        })) {
-            eprintln!("[ORDER WALLET CHECK ERROR] {}", err);
+    // Published while the auction runs, so it is worked out from the visible book only and can
+    // differ from where the uncross, which also trades the hidden reserve, ends up.
+    fn indicative(&self) -> (Option<Price>, Amount) {
+        match self.phase {
+            MarketPhase::Auction { .. } => match self.equilibrium(false) {
+                Some((price, volume)) => (Some(price), volume),
+                None => (None, dec!(0)),
+            },
+            _ => (None, dec!(0)),
         }
+    }
 
-        match oneshot_rx.await {
-            Ok(msg) => {
-                if !msg.success {
-                    if let Some(responder) = order.responder {
-                        if let Err(_) = responder.send(OrderResponse {
-                            status: "order could not be made, insufficient balance".to_string(),
-                            filled: dec!(0),
-                            remaining: dec!(0),
-                        }) {
-                            eprintln!(
-                                "[ORDER WALLET CHECK RESPONSE ERROR] cannot send error message back"
-                            );
-                        }
-                    }
-                    return;
+    // Ends an auction: bids at or above the equilibrium price take, best first, from the asks
+    // at or below it, and every one of those trades goes off at the equilibrium price.
+    fn uncross(&mut self) {
+        let Some((price, volume)) = self.equilibrium(true) else {
+            println!("[AUCTION] {} nothing to uncross", self.instrument.symbol);
+            return;
+        };
+        println!(
+            "[AUCTION] {} uncrossing {} @ {}",
+            self.instrument.symbol, volume, price
+        );
+
+        self.uncross_price = Some(price);
+        while self.best_ask_at_or_below(price) {
+            let Some((&level, queue)) = self.bids.iter_mut().next_back() else {
+                break;
+            };
+            if level < price {
+                break;
+            }
+            let Some(mut bid) = queue.pop_front() else {
+                break;
+            };
+            if queue.is_empty() {
+                self.bids.remove(&level);
+            }
+            self.mark_dirty(Side::BID, level);
+
+            if bid.reduce_only
+                && trim_reduce_only(&self.net_positions, &self.balances, &mut bid) == dec!(0)
+            {
+                self.orders.remove(&bid.id);
+                self.l3_events.push(L3Event::new(L3EventKind::Cancel, &bid));
+                continue;
+            }
+
+            // the whole order takes part, not just an iceberg's visible slice, and only the
+            // asks inside the uncross price are crossed
+            bid.amount += bid.reserve;
+            bid.reserve = dec!(0);
+            bid.price = price;
+            let filled = self.match_buy(&mut bid);
+            bid.price = level;
+
+            if filled > dec!(0) {
+                if let Some(brackets) = bid.brackets.take() {
+                    attach_brackets(&self.position_tx, &bid.user_id, &bid.symbol, brackets);
                 }
             }
-            Err(_) => {
-                eprintln!("[ORDER WALLET CHECK ERROR] wallet task dropped oneshot sender");
-                return;
+            if bid.cancelled_by_self_trade() {
+                self.orders.remove(&bid.id);
+                self.balances.release(&bid.user_id, bid.amount * bid.price);
+                self.l3_events.push(L3Event::new(L3EventKind::Cancel, &bid));
+                continue;
+            }
+            if let Some(display_amount) = bid.display_amount {
+                if bid.amount > display_amount {
+                    bid.reserve = bid.amount - display_amount;
+                    bid.amount = display_amount;
+                }
             }
+            if filled > dec!(0) {
+                self.l3_events.push(L3Event::executed(&bid, filled));
+            }
+            if bid.amount == dec!(0) {
+                self.orders.remove(&bid.id);
+                continue;
+            }
+
+            // only the last bid to take can be left over, it keeps its place at the front
+            self.bids.entry(level).or_default().push_front(bid);
+            break;
         }
+        self.uncross_price = None;
+    }
 
-        let mut filled = dec!(0);
-        let mut prices_to_remove: Vec<Price> = Vec::new();
+    fn best_ask_at_or_below(&self, price: Price) -> bool {
+        self.asks
+            .keys()
+            .next()
+            .is_some_and(|&best_ask| best_ask <= price)
+    }
 
-        // descending price order for matching with best bids
-        for (&price, queue) in self.bids.iter_mut().rev() {
-            if order.order_type == LIMIT && price < order.price {
-                match self.asks.get_mut(&order.price) {
-                    Some(sells) => {
-                        if let Some(responder) = order.responder.take() {
-                            let _ = responder.send(OrderResponse {
-                                status: "order partially filled, remaining added to queue!"
-                                    .to_string(),
-                                filled,
-                                remaining: order.amount,
-                            });
+    pub fn market_status(&self) -> MarketStatus {
+        let (indicative_price, indicative_volume) = self.indicative();
+        MarketStatus {
+            symbol: self.instrument.symbol.to_string(),
+            phase: self.phase,
+            indicative_price,
+            indicative_volume,
+            index_price: self.index_price,
+            mark_price: self.mark_price,
+            last_trade_price: self.last_trade_price,
+            best_bid: self.best_bid,
+            best_ask: self.best_ask,
+        }
+    }
+
+    // Places two linked orders. The first goes in as usual; if it traded straight away or didn't
+    // make it into the book, the second is rejected. If the second doesn't make it, the first
+    // is pulled again, so either both orders are live or neither is.
+    pub fn insert_oco(&mut self, first: Order, mut second: Order) {
+        let (first_id, second_id) = (first.id.clone(), second.id.clone());
+        self.oco_links.insert(first_id.clone(), second_id.clone());
+        self.oco_links.insert(second_id.clone(), first_id.clone());
+
+        self.insert_order(first);
+        if !self.oco_links.contains_key(&first_id) || !self.is_live(&first_id) {
+            self.unlink_oco(&first_id);
+            if let Some(responder) = second.responder.take() {
+                let _ = responder.send(OrderResponse {
+                    order_id: second.id.clone(),
+                    status: format!("oco cancelled, linked order {} is no longer open", first_id),
+                    filled: dec!(0),
+                    remaining: second.amount,
+                    fee: dec!(0),
+                });
+            }
+            return;
+        }
+
+        self.insert_order(second);
+        if self.oco_links.contains_key(&second_id) && !self.is_live(&second_id) {
+            self.unlink_oco(&second_id);
+            if let Some(order) = self.remove_order(&first_id) {
+                println!(
+                    "[OCO CANCELLED] {} (linked order {} rejected)",
+                    order, second_id
+                );
+            }
+        }
+    }
+
+    // Whether the order is resting in this book or waiting for its trigger.
+    pub fn is_live(&self, id: &str) -> bool {
+        self.orders.contains_key(id) || self.triggers.owner(id).is_some()
+    }
+
+    // Drops the link of `id` in both directions, returns the order it was linked with.
+    fn unlink_oco(&mut self, id: &str) -> Option<String> {
+        let sibling = self.oco_links.remove(id)?;
+        self.oco_links.remove(&sibling);
+        Some(sibling)
+    }
+
+    // Cancels the other half of every linked order that traded during the last match.
+    fn settle_oco(&mut self) {
+        for id in std::mem::take(&mut self.oco_filled) {
+            let Some(sibling) = self.unlink_oco(&id) else {
+                continue;
+            };
+            if let Some(order) = self.remove_order(&sibling) {
+                println!("[OCO CANCELLED] {} (linked order {} filled)", order, id);
+            }
+        }
+    }
+
+    fn insert_stop(&mut self, mut order: Order) {
+        if order.order_type == TRAILING_STOP {
+            let responder = order.responder.take();
+            let id = order.id.clone();
+            let remaining = order.amount;
+            self.history.accept(&id, remaining);
+            let status = match self.triggers.insert_trailing(order) {
+                Some(trigger_price) => format!(
+                    "trailing stop accepted, trigger currently at mark price {}",
+                    trigger_price
+                ),
+                None => "trailing stop accepted, waiting for the first mark price".to_string(),
+            };
+            if let Some(responder) = responder {
+                let _ = responder.send(OrderResponse {
+                    order_id: id,
+                    status,
+                    filled: dec!(0),
+                    remaining,
+                    fee: dec!(0),
+                });
+            }
+            return;
+        }
+
+        let Some(trigger_price) = order.trigger_price else {
+            if let Some(responder) = order.responder.take() {
+                let _ = responder.send(OrderResponse {
+                    order_id: order.id.clone(),
+                    status: "stop order rejected, missing trigger price".to_string(),
+                    filled: dec!(0),
+                    remaining: dec!(0),
+                    fee: dec!(0),
+                });
+            }
+            return;
+        };
+
+        self.history.accept(&order.id, order.amount);
+        if let Some(responder) = order.responder.take() {
+            let _ = responder.send(OrderResponse {
+                order_id: order.id.clone(),
+                status: format!(
+                    "stop order accepted, waiting for mark price {}",
+                    trigger_price
+                ),
+                filled: dec!(0),
+                remaining: order.amount,
+                fee: dec!(0),
+            });
+        }
+
+        self.triggers.insert(order, trigger_price);
+    }
+
+    // Called with every mark price the position tracker computes. Stops it crosses are fed
+    // through the regular order path, the same way liquidation orders are.
+    pub fn update_mark_price(&mut self, mark_price: Price, index_price: Price) {
+        self.mark_price = Some(mark_price);
+        self.index_price = Some(index_price);
+        // stops wait out halts and auctions instead of being rejected by them
+        if self.phase != MarketPhase::Continuous {
+            return;
+        }
+
+        // linked stops that fired together with their sibling, only the first of the pair goes in
+        let mut cancelled: Vec<String> = Vec::new();
+
+        for order in self.triggers.triggered(mark_price) {
+            if cancelled.contains(&order.id) {
+                println!("[OCO CANCELLED] {} (linked stop triggered)", order);
+                continue;
+            }
+            if let Some(sibling) = self.unlink_oco(&order.id) {
+                match self.remove_order(&sibling) {
+                    Some(sibling) => {
+                        println!("[OCO CANCELLED] {} (linked stop triggered)", sibling)
+                    }
+                    None => cancelled.push(sibling),
+                }
+            }
+
+            println!("[STOP TRIGGERED] {} (mark {})", order, mark_price);
+            self.insert_order(order);
+        }
+    }
+
+    // Current trigger level of a stop order, None for a trailing stop that hasn't seen a mark yet.
+    pub fn trigger_level(&self, id: &str, user_id: &str) -> Result<Option<Price>, BookError> {
+        let order = self
+            .triggers
+            .get(id)
+            .ok_or_else(|| BookError::OrderNotFound(id.to_string()))?;
+        if order.user_id != user_id {
+            return Err(BookError::NotOrderOwner(id.to_string()));
+        }
+        Ok(order.trigger_price)
+    }
+
+    pub fn handle_trigger_level(&self, query: TriggerLevelQuery) {
+        let result = self.trigger_level(&query.id, &query.user_id);
+
+        if let Some(responder) = query.responder {
+            if responder.send(result).is_err() {
+                eprintln!("[TRIGGER LEVEL RESPONSE ERROR] cannot send trigger level back");
+            }
+        }
+    }
+
+    // Whether a limit order at `price` would match against the other side right now.
+    fn would_take(&self, side: Side, price: Price) -> bool {
+        if self.collecting() {
+            return false;
+        }
+        match side {
+            Side::BID => self.best_ask.is_some_and(|best_ask| price >= best_ask),
+            Side::ASK => self.best_bid.is_some_and(|best_bid| price <= best_bid),
+        }
+    }
+
+    // Under cancel_taker and cancel_both reaching an own order cancels the taker mid-sweep, after
+    // it may already have partially filled, so a fill or kill that crosses one can't go in.
+    fn meets_own_order(&self, order: &Order) -> bool {
+        if !matches!(
+            order.self_trade_prevention,
+            Some(SelfTradePrevention::CancelTaker | SelfTradePrevention::CancelBoth)
+        ) {
+            return false;
+        }
+        let has_own = |(price, queue): (&Price, &VecDeque<Order>)| -> Option<bool> {
+            order
+                .crosses(*price)
+                .then(|| queue.iter().any(|o| o.user_id == order.user_id))
+        };
+
+        match order.side {
+            Side::BID => self.asks.iter().map_while(has_own).any(|own| own),
+            Side::ASK => self.bids.iter().rev().map_while(has_own).any(|own| own),
+        }
+    }
+
+    // Amount resting on the other side at prices this order would trade at. Reduce-only makers
+    // count only as far as matching would trim them, i.e. what their position still allows after
+    // the fills of the same user's orders ahead of them in the sweep.
+    fn available_liquidity(&self, order: &Order) -> Amount {
+        let levels: Box<dyn Iterator<Item = (&Price, &VecDeque<Order>)>> = match order.side {
+            Side::BID => Box::new(self.asks.iter()),
+            Side::ASK => Box::new(self.bids.iter().rev()),
+        };
+
+        // user id -> how much their position can still shrink by
+        let mut reducible: HashMap<&str, Amount> = HashMap::new();
+        let mut available = dec!(0);
+        for (price, queue) in levels {
+            if !order.crosses(*price) {
+                break;
+            }
+            for maker in queue {
+                if order.self_trade_prevention.is_some() && maker.user_id == order.user_id {
+                    continue;
+                }
+                let left = reducible.entry(maker.user_id.as_str()).or_insert_with(|| {
+                    reducible_amount(&self.net_positions, &maker.user_id, maker.side)
+                });
+                let amount = if maker.reduce_only {
+                    maker.remaining().min(*left)
+                } else {
+                    maker.remaining()
+                };
+                *left = (*left - amount).max(dec!(0));
+                available += amount;
+            }
+        }
+        available
+    }
+
+    pub fn handle_buy(&mut self, mut order: Order) {
+        if !self
+            .balances
+            .reserve(&order.user_id, order.amount * order.price)
+        {
+            Self::reject_insufficient_balance(order);
+            return;
+        }
+        self.history.accept(&order.id, order.remaining());
+
+        let filled = self.match_buy(&mut order);
+        self.respond_and_rest(order, filled);
+    }
+
+    pub fn handle_sell(&mut self, mut order: Order) {
+        if !self
+            .balances
+            .reserve(&order.user_id, order.amount * order.price)
+        {
+            Self::reject_insufficient_balance(order);
+            return;
+        }
+        self.history.accept(&order.id, order.remaining());
+
+        let filled = self.match_sell(&mut order);
+        self.respond_and_rest(order, filled);
+    }
+
+    fn reject_insufficient_balance(order: Order) {
+        if let Some(responder) = order.responder {
+            if responder
+                .send(OrderResponse {
+                    order_id: order.id,
+                    status: "order could not be made, insufficient balance".to_string(),
+                    filled: dec!(0),
+                    remaining: dec!(0),
+                    fee: dec!(0),
+                })
+                .is_err()
+            {
+                eprintln!("[ORDER WALLET CHECK RESPONSE ERROR] cannot send error message back");
+            }
+        }
+    }
+
+    // Matches a buy against the asks, returns the filled amount. Leaves the remainder in `order`.
+    fn match_buy(&mut self, order: &mut Order) -> Amount {
+        let mut filled: Amount = dec!(0);
+        if self.collecting() {
+            return filled;
+        }
+
+        let mut prices_to_remove: Vec<Price> = Vec::new();
+
+        // ascending price order
+        for (&price, queue) in self.asks.iter_mut() {
+            if !order.crosses(price) {
+                break;
+            }
+            self.dirty_asks.insert(price);
+            // an auction uncross trades every level at the one equilibrium price
+            let trade_price = self.uncross_price.unwrap_or(price);
+
+            // Makers dropping out on the way (reduce-only trims, self-trade cancels) leave part of
+            // an allocation unfilled, so the level is shared out again until one side runs out.
+            while order.amount > dec!(0) && !order.cancelled_by_self_trade() {
+                let mut allocations = allocate(self.instrument, queue, order.amount);
+                if allocations.iter().all(|allocation| allocation.is_zero()) {
+                    break;
+                }
+
+                let mut index = 0;
+                while index < queue.len() {
+                    if allocations[index].is_zero() {
+                        index += 1;
+                        continue;
+                    }
+                    let ask = &mut queue[index];
+
+                    if ask.reduce_only {
+                        let visible = ask.amount;
+                        if trim_reduce_only(&self.net_positions, &self.balances, ask) == dec!(0) {
+                            if let Some(ask) = queue.remove(index) {
+                                allocations.remove(index);
+                                self.orders.remove(&ask.id);
+                                self.l3_events.push(L3Event::new(L3EventKind::Cancel, &ask));
+                            }
+                            continue;
+                        }
+                        if ask.amount != visible {
+                            self.l3_events.push(L3Event::new(L3EventKind::Modify, ask));
                         }
-                        sells.push_back(order);
-                        return;
                     }
-                    None => {
-                        let mut new_queue: VecDeque<Order> = VecDeque::new();
-
-                        if let Some(responder_tx) = order.responder.take() {
-                            if let Err(_) = responder_tx.send(OrderResponse {
-                                status: "could not match, adding to queue".to_string(),
-                                filled: filled,
-                                remaining: order.amount,
-                            }) {
-                                eprintln!("some shit happened to oneshot responder, can't send...");
+
+                    if ask.user_id == order.user_id && order.self_trade_prevention.is_some() {
+                        order.self_trade_prevented = true;
+                        if order.self_trade_prevention != Some(SelfTradePrevention::CancelTaker) {
+                            if let Some(ask) = queue.remove(index) {
+                                allocations.remove(index);
+                                self.orders.remove(&ask.id);
+                                self.balances
+                                    .release(&ask.user_id, ask.remaining() * ask.price);
+                                self.l3_events.push(L3Event::new(L3EventKind::Cancel, &ask));
                             }
                         }
+                        if order.cancelled_by_self_trade() {
+                            break;
+                        }
+                        continue;
+                    }
 
-                        new_queue.push_back(order);
-                        self.asks.insert(price, new_queue);
-                        return;
+                    let trade_amount = allocations[index].min(ask.amount).min(order.amount);
+                    println!(
+                        "Matched BUY {} with SELL {} @ {} for {}",
+                        order.user_id, ask.user_id, trade_price, trade_amount
+                    );
+
+                    order.amount -= trade_amount;
+                    ask.amount -= trade_amount;
+                    let notional = trade_amount * trade_price;
+                    let (maker_tier, taker_tier) = (
+                        self.tiers.tier_of(&ask.user_id),
+                        self.tiers.tier_of(&order.user_id),
+                    );
+                    let (maker_fee, taker_fee) = self.instrument.fill_fees(
+                        notional,
+                        maker_tier,
+                        taker_tier,
+                        self.uncross_price.is_some(),
+                    );
+                    self.tiers.record(&ask.user_id, notional, now_secs());
+                    self.tiers.record(&order.user_id, notional, now_secs());
+                    self.balances.pay_fee(&ask.user_id, maker_fee);
+                    self.balances.pay_fee(&order.user_id, taker_fee);
+                    ask.fees += maker_fee;
+                    order.fees += taker_fee;
+                    filled += trade_amount;
+                    self.recent_trades.push(now_secs(), trade_price);
+                    self.l3_events.push(L3Event::executed(ask, trade_amount));
+                    self.history
+                        .fill(&ask.id, trade_amount, trade_price, now_secs());
+                    self.history
+                        .fill(&order.id, trade_amount, trade_price, now_secs());
+
+                    //  TODO: try_send does not give enough fucks to try again if the buffer is full
+                    //        it will simply throw an error, catch it and either drop the trade,
+                    //        see why tf is is blocked as it shouldn't as it has 10k limit or try
+                    //        again.
+
+                    // let the position tracker know the trade just happened here
+                    if let Err(err) = self.position_tx.send(EngineEvent::Trade(Trade {
+                        symbol: order.symbol.clone(),
+                        long_id: order.user_id.clone(),
+                        short_id: ask.user_id.clone(),
+                        long_leverage: order.leverage,
+                        short_leverage: ask.leverage,
+                        amount: trade_amount,
+                        price: trade_price,
+                        long_fee: taker_fee,
+                        short_fee: maker_fee,
+                        long_fee_tier: taker_tier.level,
+                        short_fee_tier: maker_tier.level,
+                    })) {
+                        eprintln!("{}", err);
+                    }
+                    record_trade(
+                        &mut self.net_positions,
+                        &order.user_id,
+                        &ask.user_id,
+                        trade_amount,
+                    );
+
+                    if let Some(brackets) = ask.brackets.take() {
+                        attach_brackets(&self.position_tx, &ask.user_id, &ask.symbol, brackets);
+                    }
+                    if self.oco_links.contains_key(&ask.id) {
+                        self.oco_filled.push(ask.id.clone());
+                    }
+
+                    if ask.amount == dec!(0) {
+                        allocations.remove(index);
+                        if let Some(mut ask) = queue.remove(index) {
+                            if ask.reserve > dec!(0) {
+                                // iceberg slice used up, show the next one at the back of the level
+                                ask.refill();
+                                self.l3_events.push(L3Event::new(L3EventKind::Add, &ask));
+                                queue.push_back(ask);
+                                allocations.push_back(dec!(0));
+                            } else {
+                                self.orders.remove(&ask.id);
+                            }
+                        }
+                    } else {
+                        index += 1;
+                    }
+                    if order.amount == dec!(0) {
+                        break;
                     }
                 }
             }
 
-            while let Some(bid) = queue.front_mut() {
-                let trade_amount = order.amount.min(bid.amount);
-                println!(
-                    "Matched SELL {} with BUY {} @ {} for {}",
-                    order.user_id, bid.user_id, price, trade_amount
-                );
+            if queue.is_empty() {
+                prices_to_remove.push(price);
+            }
 
-                order.amount -= trade_amount;
-                bid.amount -= trade_amount;
-                filled += trade_amount;
-
-                // let the position tracker know the trade just happened here
-                if let Err(e) = self.position_tx.send(EngineEvent::Trade(Trade {
-                    long_id: order.user_id.clone(),
-                    short_id: bid.user_id.clone(),
-                    long_leverage: order.leverage,
-                    short_leverage: bid.leverage,
-                    amount: trade_amount,
-                    price,
-                })) {
-                    println!("[POSITION SENDER ERROR] {}", e);
-                }
+            if order.amount == dec!(0) || order.cancelled_by_self_trade() {
+                break;
+            }
+        }
 
-                if bid.amount == dec!(0) {
-                    queue.pop_front();
-                }
-                if order.amount == dec!(0) {
+        for price in prices_to_remove {
+            self.asks.remove(&price);
+        }
+
+        if filled > dec!(0) && self.oco_links.contains_key(&order.id) {
+            self.oco_filled.push(order.id.clone());
+        }
+
+        filled
+    }
+
+    // Matches a sell against the bids, returns the filled amount. Leaves the remainder in `order`.
+    fn match_sell(&mut self, order: &mut Order) -> Amount {
+        let mut filled = dec!(0);
+        if self.collecting() {
+            return filled;
+        }
+        let mut prices_to_remove: Vec<Price> = Vec::new();
+
+        // descending price order for matching with best bids
+        for (&price, queue) in self.bids.iter_mut().rev() {
+            if !order.crosses(price) {
+                break;
+            }
+            self.dirty_bids.insert(price);
+            // an auction uncross trades every level at the one equilibrium price
+            let trade_price = self.uncross_price.unwrap_or(price);
+
+            // Makers dropping out on the way (reduce-only trims, self-trade cancels) leave part of
+            // an allocation unfilled, so the level is shared out again until one side runs out.
+            while order.amount > dec!(0) && !order.cancelled_by_self_trade() {
+                let mut allocations = allocate(self.instrument, queue, order.amount);
+                if allocations.iter().all(|allocation| allocation.is_zero()) {
                     break;
                 }
+
+                let mut index = 0;
+                while index < queue.len() {
+                    if allocations[index].is_zero() {
+                        index += 1;
+                        continue;
+                    }
+                    let bid = &mut queue[index];
+
+                    if bid.reduce_only {
+                        let visible = bid.amount;
+                        if trim_reduce_only(&self.net_positions, &self.balances, bid) == dec!(0) {
+                            if let Some(bid) = queue.remove(index) {
+                                allocations.remove(index);
+                                self.orders.remove(&bid.id);
+                                self.l3_events.push(L3Event::new(L3EventKind::Cancel, &bid));
+                            }
+                            continue;
+                        }
+                        if bid.amount != visible {
+                            self.l3_events.push(L3Event::new(L3EventKind::Modify, bid));
+                        }
+                    }
+
+                    if bid.user_id == order.user_id && order.self_trade_prevention.is_some() {
+                        order.self_trade_prevented = true;
+                        if order.self_trade_prevention != Some(SelfTradePrevention::CancelTaker) {
+                            if let Some(bid) = queue.remove(index) {
+                                allocations.remove(index);
+                                self.orders.remove(&bid.id);
+                                self.balances
+                                    .release(&bid.user_id, bid.remaining() * bid.price);
+                                self.l3_events.push(L3Event::new(L3EventKind::Cancel, &bid));
+                            }
+                        }
+                        if order.cancelled_by_self_trade() {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    let trade_amount = allocations[index].min(bid.amount).min(order.amount);
+                    println!(
+                        "Matched SELL {} with BUY {} @ {} for {}",
+                        order.user_id, bid.user_id, trade_price, trade_amount
+                    );
+
+                    order.amount -= trade_amount;
+                    bid.amount -= trade_amount;
+                    let notional = trade_amount * trade_price;
+                    let (maker_tier, taker_tier) = (
+                        self.tiers.tier_of(&bid.user_id),
+                        self.tiers.tier_of(&order.user_id),
+                    );
+                    let (maker_fee, taker_fee) = self.instrument.fill_fees(
+                        notional,
+                        maker_tier,
+                        taker_tier,
+                        self.uncross_price.is_some(),
+                    );
+                    self.tiers.record(&bid.user_id, notional, now_secs());
+                    self.tiers.record(&order.user_id, notional, now_secs());
+                    self.balances.pay_fee(&bid.user_id, maker_fee);
+                    self.balances.pay_fee(&order.user_id, taker_fee);
+                    bid.fees += maker_fee;
+                    order.fees += taker_fee;
+                    filled += trade_amount;
+                    self.recent_trades.push(now_secs(), trade_price);
+                    self.l3_events.push(L3Event::executed(bid, trade_amount));
+                    self.history
+                        .fill(&bid.id, trade_amount, trade_price, now_secs());
+                    self.history
+                        .fill(&order.id, trade_amount, trade_price, now_secs());
+
+                    // let the position tracker know the trade just happened here
+                    if let Err(e) = self.position_tx.send(EngineEvent::Trade(Trade {
+                        symbol: order.symbol.clone(),
+                        long_id: bid.user_id.clone(),
+                        short_id: order.user_id.clone(),
+                        long_leverage: bid.leverage,
+                        short_leverage: order.leverage,
+                        amount: trade_amount,
+                        price: trade_price,
+                        long_fee: maker_fee,
+                        short_fee: taker_fee,
+                        long_fee_tier: maker_tier.level,
+                        short_fee_tier: taker_tier.level,
+                    })) {
+                        println!("[POSITION SENDER ERROR] {}", e);
+                    }
+                    record_trade(
+                        &mut self.net_positions,
+                        &bid.user_id,
+                        &order.user_id,
+                        trade_amount,
+                    );
+
+                    if let Some(brackets) = bid.brackets.take() {
+                        attach_brackets(&self.position_tx, &bid.user_id, &bid.symbol, brackets);
+                    }
+                    if self.oco_links.contains_key(&bid.id) {
+                        self.oco_filled.push(bid.id.clone());
+                    }
+
+                    if bid.amount == dec!(0) {
+                        allocations.remove(index);
+                        if let Some(mut bid) = queue.remove(index) {
+                            if bid.reserve > dec!(0) {
+                                // iceberg slice used up, show the next one at the back of the level
+                                bid.refill();
+                                self.l3_events.push(L3Event::new(L3EventKind::Add, &bid));
+                                queue.push_back(bid);
+                                allocations.push_back(dec!(0));
+                            } else {
+                                self.orders.remove(&bid.id);
+                            }
+                        }
+                    } else {
+                        index += 1;
+                    }
+                    if order.amount == dec!(0) {
+                        break;
+                    }
+                }
             }
 
             if queue.is_empty() {
                 prices_to_remove.push(price);
             }
 
-            if order.amount == dec!(0) {
+            if order.amount == dec!(0) || order.cancelled_by_self_trade() {
                 break;
             }
         }
@@ -439,31 +1851,460 @@ impl OrderBook {
             self.bids.remove(&price);
         }
 
-        let dynamic_status = |order_type: OrderType| match order_type {
-            MARKET => "disregarding remaining amount.".to_string(),
-            LIMIT => "adding remaining in queue.".to_string(),
-        };
+        if filled > dec!(0) && self.oco_links.contains_key(&order.id) {
+            self.oco_filled.push(order.id.clone());
+        }
 
-        if order.amount > dec!(0) {
-            if let Some(responder) = order.responder.take() {
-                let _ = responder.send(OrderResponse {
-                    status: dynamic_status(order.order_type.clone()),
-                    filled,
-                    remaining: order.amount,
-                });
-            }
-            if order.order_type == LIMIT {
-                self.asks.entry(order.price).or_default().push_back(order);
+        filled
+    }
+
+    // Reports the outcome of matching back to the client and rests whatever is left of a limit
+    // order on its own side of the book.
+    fn respond_and_rest(&mut self, mut order: Order, filled: Amount) {
+        if filled > dec!(0) {
+            if let Some(brackets) = order.brackets.take() {
+                attach_brackets(&self.position_tx, &order.user_id, &order.symbol, brackets);
             }
+        }
+
+        // cancel-maker lets the incoming order carry on, so it only adds a note to the status
+        let self_trade_note = if order.self_trade_prevented && !order.cancelled_by_self_trade() {
+            "self trade prevented, resting order cancelled, "
         } else {
+            ""
+        };
+
+        if order.amount == dec!(0) {
             if let Some(responder) = order.responder.take() {
                 let _ = responder.send(OrderResponse {
-                    status: "order completely filled".to_string(),
+                    order_id: order.id.clone(),
+                    status: format!("{}order completely filled", self_trade_note),
                     filled,
                     remaining: dec!(0),
+                    fee: order.fees,
                 });
             }
+            return;
+        }
+
+        let auction_until = match self.phase {
+            MarketPhase::Auction { uncross_at, .. } => Some(uncross_at),
+            _ => None,
+        };
+        let status = match (&order.order_type, order.time_in_force) {
+            (LIMIT, _) if auction_until.is_some() => format!(
+                "auction running, added to queue until the uncross at {}",
+                auction_until.unwrap_or_default()
+            ),
+            _ if order.cancelled_by_self_trade() => match order.self_trade_prevention {
+                Some(SelfTradePrevention::CancelBoth) => {
+                    "self trade prevented, incoming and resting orders cancelled".to_string()
+                }
+                _ => "self trade prevented, incoming order cancelled".to_string(),
+            },
+            (LIMIT, TimeInForce::IOC) => "immediate or cancel, remaining cancelled".to_string(),
+            (LIMIT, TimeInForce::FOK) => "fill or kill, remaining cancelled".to_string(),
+            (LIMIT, TimeInForce::GTD(expires_at)) => format!(
+                "good till {}, remaining added to queue until expiry",
+                expires_at
+            ),
+            (LIMIT, _) if order.repriced_from.is_some() => format!(
+                "post only re-priced from {} to {}, added to queue!",
+                order.repriced_from.unwrap_or_default(),
+                order.price
+            ),
+            (LIMIT, _) if filled == dec!(0) => "could not match, added to queue!".to_string(),
+            (LIMIT, _) => "order partially filled, remaining added to queue!".to_string(),
+            (OrderType::MARKET, _) if order.protection_price.is_some() => format!(
+                "no more liquidity within slippage limit {}, remaining cancelled",
+                order.protection_price.unwrap_or_default().round_dp(2)
+            ),
+            _ => "disregarding remaining amount.".to_string(),
+        };
+
+        if let Some(responder) = order.responder.take() {
+            let _ = responder.send(OrderResponse {
+                order_id: order.id.clone(),
+                status: format!("{}{}", self_trade_note, status),
+                filled,
+                remaining: order.amount,
+                fee: order.fees,
+            });
+        }
+
+        let rests = matches!(order.time_in_force, TimeInForce::GTC | TimeInForce::GTD(_))
+            && !order.cancelled_by_self_trade();
+        if order.order_type == LIMIT && rests {
+            self.rest_order(order);
+        } else if order.price > dec!(0) {
+            // hand back what was held for the part that will never trade
+            self.balances
+                .release(&order.user_id, order.amount * order.price);
+        }
+    }
+
+    fn rest_order(&mut self, mut order: Order) {
+        if let Some(display_amount) = order.display_amount {
+            if order.amount > display_amount {
+                order.reserve += order.amount - display_amount;
+                order.amount = display_amount;
+            }
+        }
+
+        self.orders
+            .insert(order.id.clone(), (order.side, order.price));
+        if let TimeInForce::GTD(expires_at) = order.time_in_force {
+            self.expiries
+                .entry(expires_at)
+                .or_default()
+                .push(order.id.clone());
+        }
+
+        self.mark_dirty(order.side, order.price);
+        self.l3_events.push(L3Event::new(L3EventKind::Add, &order));
+        let levels = match order.side {
+            Side::BID => &mut self.bids,
+            Side::ASK => &mut self.asks,
+        };
+        levels.entry(order.price).or_default().push_back(order);
+    }
+
+    fn mark_dirty(&mut self, side: Side, price: Price) {
+        match side {
+            Side::BID => self.dirty_bids.insert(price),
+            Side::ASK => self.dirty_asks.insert(price),
+        };
+    }
+
+    // Finds a resting order by id, returns its side, price level and queue position.
+    fn find_order(&self, id: &str) -> Option<(Side, Price, usize)> {
+        let (side, price) = *self.orders.get(id)?;
+
+        let levels = match side {
+            Side::BID => &self.bids,
+            Side::ASK => &self.asks,
+        };
+
+        let index = levels
+            .get(&price)?
+            .iter()
+            .position(|order| order.id == id)?;
+
+        Some((side, price, index))
+    }
+
+    // Same as find_order, but only for orders owned by `user_id`.
+    fn locate_order(&self, id: &str, user_id: &str) -> Result<(Side, Price, usize), BookError> {
+        let (side, price, index) = self
+            .find_order(id)
+            .ok_or_else(|| BookError::OrderNotFound(id.to_string()))?;
+
+        if self.order_at(side, price, index).user_id != user_id {
+            return Err(BookError::NotOrderOwner(id.to_string()));
+        }
+
+        Ok((side, price, index))
+    }
+
+    // Resting order at a position returned by find_order / locate_order.
+    fn order_at(&self, side: Side, price: Price, index: usize) -> &Order {
+        match side {
+            Side::BID => &self.bids[&price][index],
+            Side::ASK => &self.asks[&price][index],
+        }
+    }
+
+    // Pulls a located order out of its level, dropping the level and the index entry with it.
+    fn take_order(&mut self, side: Side, price: Price, index: usize) -> Option<Order> {
+        self.mark_dirty(side, price);
+        let levels = match side {
+            Side::BID => &mut self.bids,
+            Side::ASK => &mut self.asks,
+        };
+
+        let queue = levels.get_mut(&price)?;
+        let order = queue.remove(index)?;
+        if queue.is_empty() {
+            levels.remove(&price);
+        }
+        self.orders.remove(&order.id);
+        self.l3_events
+            .push(L3Event::new(L3EventKind::Cancel, &order));
+
+        Some(order)
+    }
+
+    pub fn handle_cancel(&mut self, cancel: CancelOrder) {
+        let result = self
+            .cancel_order(&cancel.id, &cancel.user_id)
+            .map(|order| order.remaining());
+
+        if let Some(responder) = cancel.responder {
+            if responder.send(result).is_err() {
+                eprintln!("[ORDER CANCEL RESPONSE ERROR] cannot send cancel result back");
+            }
+        }
+    }
+
+    // Cancels an order on behalf of its owner. Cancelling one half of an oco pair cancels both.
+    pub fn cancel_order(&mut self, id: &str, user_id: &str) -> Result<Order, BookError> {
+        match self.triggers.owner(id) {
+            Some(owner) if owner != user_id => {
+                return Err(BookError::NotOrderOwner(id.to_string()))
+            }
+            Some(_) => {}
+            None => {
+                self.locate_order(id, user_id)?;
+            }
+        }
+
+        let order = self
+            .remove_order(id)
+            .ok_or_else(|| BookError::OrderNotFound(id.to_string()))?;
+
+        if let Some(sibling) = self.unlink_oco(id) {
+            if let Some(sibling) = self.remove_order(&sibling) {
+                println!(
+                    "[OCO CANCELLED] {} (linked order {} cancelled)",
+                    sibling, id
+                );
+            }
+        }
+
+        Ok(order)
+    }
+
+    // Removes a resting or stop order and hands the funds debited for it back to its owner.
+    fn remove_order(&mut self, id: &str) -> Option<Order> {
+        // stops hold no funds until they trigger, so there is nothing to credit back
+        if let Some(order) = self.triggers.remove(id) {
+            return Some(order);
+        }
+
+        let (side, price, index) = self.find_order(id)?;
+        let order = self.take_order(side, price, index)?;
+        self.update_best_prices();
+
+        self.balances
+            .release(&order.user_id, order.remaining() * order.price);
+
+        Some(order)
+    }
+
+    // Cancels the user's open orders in this book that pass the filters, returns their ids.
+    // Linked oco orders go with them like on a single cancel.
+    pub fn cancel_all(&mut self, cancel: &MassCancel) -> Vec<String> {
+        let ids: Vec<String> = self
+            .history
+            .of_user(&cancel.user_id, true)
+            .filter(|record| {
+                self.is_live(&record.id)
+                    && cancel.side.is_none_or(|side| side == record.side)
+                    && cancel.min_price.is_none_or(|min| record.price >= min)
+                    && cancel.max_price.is_none_or(|max| record.price <= max)
+            })
+            .map(|record| record.id.clone())
+            .collect();
+
+        for id in &ids {
+            if self.is_live(id) {
+                let _ = self.cancel_order(id, &cancel.user_id);
+            }
+        }
+        ids
+    }
+
+    // Finishes the history records of orders that left the book since the last call: filled
+    // makers, cancels, expiries, and orders taken out by self-trade prevention or an oco sibling.
+    pub fn settle_history(&mut self, now: u64) {
+        let gone: Vec<String> = self
+            .history
+            .open_ids()
+            .filter(|id| !self.is_live(id))
+            .cloned()
+            .collect();
+        for id in gone {
+            self.history.finish(&id, now);
+        }
+    }
+
+    pub fn order_status(&mut self, id: &str, now: u64) -> Option<OrderRecord> {
+        self.settle_history(now);
+        self.history.get(id).cloned()
+    }
+
+    pub fn order_status_by_client_id(
+        &mut self,
+        user_id: &str,
+        client_order_id: &str,
+        now: u64,
+    ) -> Option<OrderRecord> {
+        self.settle_history(now);
+        self.history.by_client_id(user_id, client_order_id).cloned()
+    }
+
+    pub fn orders_of(&mut self, user_id: &str, open: bool, now: u64) -> Vec<OrderRecord> {
+        self.settle_history(now);
+        self.history.of_user(user_id, open).cloned().collect()
+    }
+
+    // Drops good-till-date orders whose expiry has passed, called from a timer on the book thread.
+    // Ids of orders that filled or were cancelled in the meantime are simply skipped.
+    pub fn expire_orders(&mut self, now: u64) {
+        let due: Vec<u64> = self.expiries.range(..=now).map(|(&at, _)| at).collect();
+        if due.is_empty() {
+            return;
+        }
+
+        for expires_at in due {
+            for id in self.expiries.remove(&expires_at).unwrap_or_default() {
+                let Some((side, price, index)) = self.find_order(&id) else {
+                    continue;
+                };
+                if let Some(order) = self.take_order(side, price, index) {
+                    println!("[EXPIRED] {}", order);
+                    self.unlink_oco(&order.id);
+                    self.balances
+                        .release(&order.user_id, order.remaining() * order.price);
+                }
+            }
+        }
+
+        self.update_best_prices();
+    }
+
+    pub fn handle_amend(&mut self, amend: AmendOrder) {
+        let result = self.amend_order(&amend.id, &amend.user_id, amend.amount, amend.price);
+
+        if let Some(responder) = amend.responder {
+            if responder.send(result).is_err() {
+                eprintln!("[ORDER AMEND RESPONSE ERROR] cannot send amend result back");
+            }
+        }
+    }
+
+    // Changes the size and/or price of a resting order in one step on the book thread.
+    // A pure size reduction keeps the order's place in its queue, anything else sends it to the
+    // back of the (possibly new) level, matching first if the new price crosses the book.
+    pub fn amend_order(
+        &mut self,
+        id: &str,
+        user_id: &str,
+        amount: Option<Amount>,
+        price: Option<Price>,
+    ) -> Result<OrderResponse, BookError> {
+        let (side, current_price, index) = self.locate_order(id, user_id)?;
+        if let MarketPhase::Halted { until, .. } = self.phase {
+            return Err(BookError::MarketHalted(until));
+        }
+        let current = self.order_at(side, current_price, index);
+        let current_amount = current.remaining();
+        let post_only = current.post_only.is_some();
+
+        let new_amount = amount.unwrap_or(current_amount);
+        let new_price = price.unwrap_or(current_price);
+        if new_amount <= dec!(0) || new_price <= dec!(0) {
+            return Err(BookError::InvalidAmend(format!(
+                "amount and price must be > 0, got {} @ {}",
+                new_amount, new_price
+            )));
+        }
+        self.instrument
+            .check_amount(new_amount)
+            .and_then(|_| self.instrument.check_price("price", new_price))
+            .and_then(|_| self.instrument.check_notional(new_amount, new_price))
+            .map_err(BookError::InvalidAmend)?;
+        if price.is_some() {
+            self.check_price_band(new_price)
+                .map_err(BookError::InvalidAmend)?;
+        }
+        if post_only && self.would_take(side, new_price) {
+            return Err(BookError::WouldTakeLiquidity(id.to_string()));
+        }
+
+        // both sides are bounded by max_size * max_price, checked above
+        let held = current_amount * current_price;
+        let needed = new_amount * new_price;
+        if needed > held {
+            if !self.balances.reserve(user_id, needed - held) {
+                return Err(BookError::InsufficientBalance);
+            }
+        } else if needed < held {
+            self.balances.release(user_id, held - needed);
+        }
+        self.history.amend(id, new_amount, new_price, now_secs());
+
+        if new_price == current_price && new_amount <= current_amount {
+            self.mark_dirty(side, current_price);
+            let levels = match side {
+                Side::BID => &mut self.bids,
+                Side::ASK => &mut self.asks,
+            };
+            if let Some(order) = levels
+                .get_mut(&current_price)
+                .and_then(|queue| queue.get_mut(index))
+            {
+                order.shrink_to(new_amount);
+                self.l3_events
+                    .push(L3Event::new(L3EventKind::Modify, order));
+            }
+
+            return Ok(OrderResponse {
+                order_id: id.to_string(),
+                status: "order amended, queue priority kept".to_string(),
+                filled: dec!(0),
+                remaining: new_amount,
+                fee: dec!(0),
+            });
         }
+
+        let mut order = self
+            .take_order(side, current_price, index)
+            .ok_or_else(|| BookError::OrderNotFound(id.to_string()))?;
+        order.amount = new_amount;
+        order.reserve = dec!(0);
+        order.price = new_price;
+
+        let fees_before = order.fees;
+        let filled = match side {
+            Side::BID => self.match_buy(&mut order),
+            Side::ASK => self.match_sell(&mut order),
+        };
+        let remaining = order.amount;
+        let fee = order.fees - fees_before;
+        let cancelled_by_self_trade = order.cancelled_by_self_trade();
+        if cancelled_by_self_trade {
+            self.balances
+                .release(&order.user_id, remaining * order.price);
+        } else if remaining > dec!(0) {
+            self.rest_order(order);
+        }
+        self.settle_oco();
+        self.check_circuit_breaker();
+        self.update_best_prices();
+
+        let status = if cancelled_by_self_trade {
+            "order amended, then cancelled by self trade prevention"
+        } else if remaining == dec!(0) {
+            "order amended and completely filled"
+        } else if filled > dec!(0) {
+            "order amended and partially filled, remaining moved to back of queue"
+        } else {
+            "order amended, moved to back of queue"
+        };
+
+        Ok(OrderResponse {
+            order_id: id.to_string(),
+            status: status.to_string(),
+            filled,
+            remaining,
+            fee,
+        })
+    }
+
+    // Whether an auction is collecting orders, nothing matches until it uncrosses.
+    fn collecting(&self) -> bool {
+        matches!(self.phase, MarketPhase::Auction { .. }) && self.uncross_price.is_none()
     }
 
     pub fn update_best_prices(&mut self) {
@@ -478,11 +2319,8 @@ impl OrderBook {
         }
     }
 
-    pub fn get_book_depth(
-        &self,
-        levels: usize,
-    ) -> (Vec<(Decimal, Decimal)>, Vec<(Decimal, Decimal)>) {
-        let bids: Vec<(Decimal, Decimal)> = self
+    pub fn get_book_depth(&self, levels: usize) -> (DepthLevels, DepthLevels) {
+        let bids: DepthLevels = self
             .bids
             .iter()
             .rev()
@@ -490,7 +2328,7 @@ impl OrderBook {
             .map(|(&price, queue)| (price, queue.iter().map(|o| o.amount).sum()))
             .collect();
 
-        let asks: Vec<(Decimal, Decimal)> = self
+        let asks: DepthLevels = self
             .asks
             .iter()
             .take(levels)
@@ -499,4 +2337,190 @@ impl OrderBook {
 
         (bids, asks)
     }
+
+    // Sends the l3 events of the last book event, then the new visible amount of every level
+    // touched by it as one depth update, so a match sweeping several levels is one update.
+    pub fn publish_market_data(&mut self) {
+        for mut event in std::mem::take(&mut self.l3_events) {
+            self.l3_sequence += 1;
+            event.symbol = self.instrument.symbol.to_string();
+            event.sequence = self.l3_sequence;
+            event.owner = self.anonymize(&event.owner);
+            if let Err(err) = self.market_data_tx.send(MarketData::L3(event)) {
+                eprintln!("[MARKET DATA SENDER ERROR] {}", err);
+            }
+        }
+
+        // while an auction runs any book change can move the indicative price
+        let auction_moved = matches!(self.phase, MarketPhase::Auction { .. })
+            && !(self.dirty_bids.is_empty() && self.dirty_asks.is_empty());
+        if self.phase_changed || auction_moved {
+            self.phase_changed = false;
+            let (indicative_price, indicative_volume) = self.indicative();
+            if let Err(err) = self.market_data_tx.send(MarketData::Auction(AuctionUpdate {
+                symbol: self.instrument.symbol.to_string(),
+                phase: self.phase,
+                indicative_price,
+                indicative_volume,
+            })) {
+                eprintln!("[MARKET DATA SENDER ERROR] {}", err);
+            }
+        }
+
+        if self.dirty_bids.is_empty() && self.dirty_asks.is_empty() {
+            return;
+        }
+
+        let level_amount = |levels: &BTreeMap<Price, VecDeque<Order>>, price: Price| -> Amount {
+            levels
+                .get(&price)
+                .map(|queue| queue.iter().map(|o| o.amount).sum())
+                .unwrap_or_default()
+        };
+        let bids: DepthLevels = std::mem::take(&mut self.dirty_bids)
+            .into_iter()
+            .rev()
+            .map(|price| (price, level_amount(&self.bids, price)))
+            .collect();
+        let asks: DepthLevels = std::mem::take(&mut self.dirty_asks)
+            .into_iter()
+            .map(|price| (price, level_amount(&self.asks, price)))
+            .collect();
+
+        self.depth_sequence += 1;
+        if let Err(err) = self.market_data_tx.send(MarketData::Depth(DepthUpdate {
+            symbol: self.instrument.symbol.to_string(),
+            sequence: self.depth_sequence,
+            bids,
+            asks,
+        })) {
+            eprintln!("[MARKET DATA SENDER ERROR] {}", err);
+        }
+    }
+
+    fn anonymize(&self, user_id: &str) -> String {
+        format!("{:016x}", self.owner_hasher.hash_one(user_id))
+    }
+
+    pub fn l3_snapshot(&self) -> L3Snapshot {
+        let resting = |(&price, queue): (&Price, &VecDeque<Order>)| {
+            queue
+                .iter()
+                .map(|order| L3Order {
+                    order_id: order.id.clone(),
+                    owner: self.anonymize(&order.user_id),
+                    price,
+                    amount: order.amount,
+                })
+                .collect::<Vec<L3Order>>()
+        };
+
+        L3Snapshot {
+            symbol: self.instrument.symbol.to_string(),
+            sequence: self.l3_sequence,
+            bids: self.bids.iter().rev().flat_map(resting).collect(),
+            asks: self.asks.iter().flat_map(resting).collect(),
+        }
+    }
+
+    pub fn depth_snapshot(&self, levels: usize) -> DepthSnapshot {
+        let (bids, asks) = self.get_book_depth(levels);
+        DepthSnapshot {
+            symbol: self.instrument.symbol.to_string(),
+            sequence: self.depth_sequence,
+            bids,
+            asks,
+            spread: self.get_spread(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::instrument::INSTRUMENTS;
+
+    // BTC-PERP, lot size 0.001, matched with `matching`
+    fn instrument(matching: MatchingAlgorithm) -> Instrument {
+        Instrument {
+            matching,
+            ..INSTRUMENTS[0]
+        }
+    }
+
+    // A price level holding one resting order per amount, front of the queue first.
+    fn level(amounts: &[Amount]) -> VecDeque<Order> {
+        amounts
+            .iter()
+            .map(|&amount| {
+                let mut order = Order::from(&Position {
+                    user_id: "maker".to_string(),
+                    symbol: "BTC-PERP".to_string(),
+                    size: amount,
+                    entry_price: dec!(60_000),
+                    margin: dec!(0),
+                    unrealized_pnl: dec!(0),
+                    take_profit: None,
+                    stop_loss: None,
+                });
+                order.order_type = LIMIT;
+                order.price = dec!(60_000);
+                order
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fifo_fills_in_time_priority() {
+        let instrument = instrument(MatchingAlgorithm::Fifo);
+        let queue = level(&[dec!(0.3), dec!(0.5), dec!(0.2)]);
+
+        let allocations = allocate(&instrument, &queue, dec!(0.6));
+        assert_eq!(allocations, [dec!(0.3), dec!(0.3), dec!(0)]);
+    }
+
+    #[test]
+    fn fifo_stops_at_what_the_level_holds() {
+        let instrument = instrument(MatchingAlgorithm::Fifo);
+        let queue = level(&[dec!(0.1), dec!(0.2)]);
+
+        let allocations = allocate(&instrument, &queue, dec!(1));
+        assert_eq!(allocations, [dec!(0.1), dec!(0.2)]);
+    }
+
+    #[test]
+    fn pro_rata_hands_the_rounding_remainder_out_in_time_priority() {
+        let instrument = instrument(MatchingAlgorithm::ProRata);
+        let queue = level(&[dec!(0.1), dec!(0.1), dec!(0.1)]);
+
+        // a third each is 0.0333.., rounded down to 0.033 and the lot left over goes to the front
+        let allocations = allocate(&instrument, &queue, dec!(0.1));
+        assert_eq!(allocations, [dec!(0.034), dec!(0.033), dec!(0.033)]);
+        assert_eq!(allocations.iter().sum::<Amount>(), dec!(0.1));
+    }
+
+    #[test]
+    fn top_order_share_and_remainder() {
+        let instrument = instrument(MatchingAlgorithm::TopOrderProRata {
+            top_order_percent: dec!(40),
+        });
+        let queue = level(&[dec!(1), dec!(0.3), dec!(0.3)]);
+
+        // 40% of 0.101 rounds down to 0.040, the other 0.061 is split 0.030 / 0.030 and the
+        // last lot goes back to the front
+        let allocations = allocate(&instrument, &queue, dec!(0.101));
+        assert_eq!(allocations, [dec!(0.041), dec!(0.03), dec!(0.03)]);
+    }
+
+    #[test]
+    fn top_order_share_is_capped_at_the_front_order() {
+        let instrument = instrument(MatchingAlgorithm::TopOrderProRata {
+            top_order_percent: dec!(40),
+        });
+        let queue = level(&[dec!(0.1), dec!(0.5), dec!(0.5)]);
+
+        // the top order could take 0.4 but only holds 0.1, the rest is shared by the others
+        let allocations = allocate(&instrument, &queue, dec!(1));
+        assert_eq!(allocations, [dec!(0.1), dec!(0.45), dec!(0.45)]);
+    }
 }
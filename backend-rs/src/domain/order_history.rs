@@ -0,0 +1,206 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use rust_decimal_macros::dec;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::domain::order::{Amount, BookError, Order, OrderType, Price, Side};
+
+// finished orders each book keeps for status queries, the oldest are forgotten first
+const HISTORY_LEN: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderState {
+    // accepted, nothing filled yet. Stops waiting for their trigger stay here too
+    New,
+    PartiallyFilled,
+    Filled,
+    // left the book with something unfilled: cancelled, expired, the rest of an ioc or market
+    // order, or taken out by self-trade prevention or an oco sibling
+    Cancelled,
+    // never accepted by the book, nothing traded
+    Rejected,
+}
+
+impl OrderState {
+    pub fn is_open(self) -> bool {
+        matches!(self, OrderState::New | OrderState::PartiallyFilled)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct OrderRecord {
+    pub id: String,
+    pub client_order_id: Option<String>,
+    #[serde(skip)]
+    pub user_id: String,
+    pub symbol: String,
+    pub side: Side,
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+    pub price: Price,
+    pub trigger_price: Option<Price>,
+    // the whole order, filled and unfilled, as placed or last amended
+    pub amount: Amount,
+    pub filled: Amount,
+    pub remaining: Amount,
+    pub average_price: Option<Price>,
+    pub state: OrderState,
+    pub created_at: u64,
+    pub updated_at: u64,
+
+    #[serde(skip)]
+    notional: Price,
+    // set once the book took the order, a triggered stop starts over when it re-enters
+    #[serde(skip)]
+    accepted: bool,
+}
+
+// The lifecycle of every order a book has seen: open orders for as long as they are in the book
+// or waiting for their trigger, finished ones until `HISTORY_LEN` newer ones push them out.
+// Fills are recorded as they happen, an order leaving the book is noticed by `OrderBook` through
+// `open_ids` and finished here.
+#[derive(Default)]
+pub struct OrderHistory {
+    records: HashMap<String, OrderRecord>,
+    open: HashSet<String>,
+    // ids of finished orders, oldest first
+    finished: VecDeque<String>,
+}
+
+impl OrderHistory {
+    // Called whenever an order enters `OrderBook::insert_order`, including stops that triggered.
+    pub fn open(&mut self, order: &Order, now: u64) {
+        if let Some(record) = self.records.get_mut(&order.id) {
+            record.accepted = false;
+            record.order_type = order.order_type.clone();
+            record.price = order.price;
+            record.updated_at = now;
+            return;
+        }
+
+        self.open.insert(order.id.clone());
+        self.records.insert(
+            order.id.clone(),
+            OrderRecord {
+                id: order.id.clone(),
+                client_order_id: order.client_order_id.clone(),
+                user_id: order.user_id.clone(),
+                symbol: order.symbol.clone(),
+                side: order.side,
+                order_type: order.order_type.clone(),
+                price: order.price,
+                trigger_price: order.trigger_price,
+                amount: order.remaining(),
+                filled: dec!(0),
+                remaining: order.remaining(),
+                average_price: None,
+                state: OrderState::New,
+                created_at: now,
+                updated_at: now,
+                notional: dec!(0),
+                accepted: false,
+            },
+        );
+    }
+
+    // The book took the order, `amount` is what is left of it after reduce-only trimming.
+    pub fn accept(&mut self, id: &str, amount: Amount) {
+        if let Some(record) = self.records.get_mut(id) {
+            record.accepted = true;
+            record.amount = record.filled + amount;
+            record.remaining = amount;
+        }
+    }
+
+    pub fn fill(&mut self, id: &str, amount: Amount, price: Price, now: u64) {
+        if let Some(record) = self.records.get_mut(id) {
+            record.filled += amount;
+            record.remaining = (record.remaining - amount).max(dec!(0));
+            record.notional += amount * price;
+            record.average_price = Some(record.notional / record.filled);
+            record.state = OrderState::PartiallyFilled;
+            record.updated_at = now;
+        }
+    }
+
+    pub fn amend(&mut self, id: &str, remaining: Amount, price: Price, now: u64) {
+        if let Some(record) = self.records.get_mut(id) {
+            record.amount = record.filled + remaining;
+            record.remaining = remaining;
+            record.price = price;
+            record.updated_at = now;
+        }
+    }
+
+    pub fn open_ids(&self) -> impl Iterator<Item = &String> {
+        self.open.iter()
+    }
+
+    // The order is no longer in the book, its final state follows from how far it got.
+    pub fn finish(&mut self, id: &str, now: u64) {
+        if !self.open.remove(id) {
+            return;
+        }
+        if let Some(record) = self.records.get_mut(id) {
+            record.state = if !record.accepted {
+                OrderState::Rejected
+            } else if record.remaining.is_zero() {
+                OrderState::Filled
+            } else {
+                OrderState::Cancelled
+            };
+            record.updated_at = now;
+        }
+
+        self.finished.push_back(id.to_string());
+        while self.finished.len() > HISTORY_LEN {
+            if let Some(id) = self.finished.pop_front() {
+                self.records.remove(&id);
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&OrderRecord> {
+        self.records.get(id)
+    }
+
+    // The latest order the user placed with this client order id.
+    pub fn by_client_id(&self, user_id: &str, client_order_id: &str) -> Option<&OrderRecord> {
+        self.records
+            .values()
+            .filter(|record| {
+                record.user_id == user_id
+                    && record.client_order_id.as_deref() == Some(client_order_id)
+            })
+            .max_by_key(|record| record.created_at)
+    }
+
+    pub fn of_user<'a>(
+        &'a self,
+        user_id: &'a str,
+        open: bool,
+    ) -> impl Iterator<Item = &'a OrderRecord> {
+        self.records
+            .values()
+            .filter(move |record| record.user_id == user_id && record.state.is_open() == open)
+    }
+}
+
+pub struct OrderStatusQuery {
+    // order id, or the client order id with `by_client_id`
+    pub id: String,
+    pub user_id: String,
+    pub by_client_id: bool,
+
+    pub responder: Option<oneshot::Sender<Result<OrderRecord, BookError>>>,
+}
+
+pub struct OrdersQuery {
+    pub user_id: String,
+    // open orders, or the recently finished ones
+    pub open: bool,
+
+    pub responder: Option<oneshot::Sender<Vec<OrderRecord>>>,
+}
@@ -6,19 +6,15 @@ use tokio::sync::{
     mpsc::{Sender, UnboundedReceiver, UnboundedSender},
     oneshot, Mutex,
 };
-use uuid::Uuid;
 
 use std::collections::hash_map::Entry;
 
 use crate::{
     broadcast_trade,
     domain::{
-        oracle::BtcPrice,
-        order::{
-            Order,
-            OrderType::MARKET,
-            Side::{ASK, BID},
-        },
+        oracle::IndexPrice,
+        order::Order,
+        utils::now_secs,
         wallet::{WalletCreditMessage, WalletDebitMessage, WalletEvent, WalletOneshotReply},
     },
     types::OrderBookMessage,
@@ -29,17 +25,52 @@ use tokio::sync::mpsc;
 
 pub struct Position {
     pub user_id: String,
+    pub symbol: String,
     pub size: Decimal,
     pub entry_price: Decimal,
     pub margin: Decimal,
     pub unrealized_pnl: Decimal,
+    pub take_profit: Option<Decimal>,
+    pub stop_loss: Option<Decimal>,
+}
+
+// Take-profit / stop-loss mark prices attached to a position. When one is hit the whole position
+// is closed at market, so the close always matches the position's size at that moment.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Brackets {
+    pub take_profit: Option<Decimal>,
+    pub stop_loss: Option<Decimal>,
+}
+
+impl Brackets {
+    pub fn validate(&self) -> Result<(), String> {
+        for price in [self.take_profit, self.stop_loss].into_iter().flatten() {
+            if price <= dec!(0) {
+                return Err(format!(
+                    "take profit / stop loss must be > 0, got {}",
+                    price
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct BracketMessage {
+    pub user_id: String,
+    pub symbol: String,
+    pub brackets: Brackets,
+
+    pub responder: Option<oneshot::Sender<Result<(), String>>>,
 }
 
 pub type PositionMap = HashMap<String, Position>;
 
 pub type BookLiquidationTx = Sender<OrderBookMessage>;
 
+// Positions and mark price of a single instrument.
 pub struct PositionTracker {
+    symbol: String,
     positions: PositionMap,
     book_liquidation_tx: BookLiquidationTx,
     mark_price: Decimal,
@@ -47,66 +78,97 @@ pub struct PositionTracker {
     current_funding_rate: Decimal,
     funding_rate_window: Vec<Decimal>,
     wallet_tx: UnboundedSender<WalletEvent>,
+    // user id -> when (unix seconds) the last close for their position went to the book
+    closing: HashMap<String, u64>,
 }
 
 const LIQUIDATION_THRESHOLD: Decimal = dec!(0.8);
+// seconds a close gets to fill before the same position is sent another one, one that found no
+// liquidity is gone without a trace, the book doesn't answer closes
+const CLOSE_RETRY: u64 = 5;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Trade {
+    pub symbol: String,
     pub long_id: String,
     pub short_id: String,
     pub long_leverage: Decimal,
     pub short_leverage: Decimal,
     pub amount: Decimal,
     pub price: Decimal,
+    // what each side paid in fees for this fill, negative for a maker rebate
+    pub long_fee: Decimal,
+    pub short_fee: Decimal,
+    // VIP fee tier level each side was charged at
+    pub long_fee_tier: u8,
+    pub short_fee_tier: u8,
 }
 
 impl fmt::Display for Trade {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "[TRADE] {} bought {} @ {} from {}",
-            self.long_id, self.amount, self.price, self.short_id
+            "[TRADE] {} bought {} {} @ {} from {}",
+            self.long_id, self.amount, self.symbol, self.price, self.short_id
         )
     }
 }
 
+#[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum Sides {
     LONG,
     SHORT,
 }
 
 pub struct FundingRatePaymentMessage {
+    pub symbol: String,
     pub side: Sides,
 }
 
 pub enum EngineEvent {
     Trade(Trade),
+    #[allow(dead_code)]
     FundingRatePayment(FundingRatePaymentMessage),
+    SetBrackets(BracketMessage),
 }
 
 fn adjust_for_leverage(margin: Decimal, leverage: Decimal) -> Decimal {
     margin / leverage
 }
 
+// A trade that flips a position leaves its take-profit / stop-loss on the wrong side of the
+// mark for the new direction, so they are dropped.
+fn clear_brackets_on_flip(position: &mut Position, new_size: Decimal) {
+    if (position.size > dec!(0)) != (new_size > dec!(0)) {
+        position.take_profit = None;
+        position.stop_loss = None;
+    }
+}
+
 impl PositionTracker {
     pub fn new(
+        symbol: &str,
         book_liquidation_tx: BookLiquidationTx,
         wallet_tx: UnboundedSender<WalletEvent>,
     ) -> PositionTracker {
         PositionTracker {
+            symbol: symbol.to_string(),
             positions: PositionMap::new(),
             book_liquidation_tx,
             last_traded_price: dec!(0),
             mark_price: dec!(0),
             current_funding_rate: dec!(0),
             funding_rate_window: Vec::new(),
-            wallet_tx: wallet_tx,
+            wallet_tx,
+            closing: HashMap::new(),
         }
     }
 
     pub fn update_position(&mut self, trade: &Trade) {
         let mut positions_to_remove: Vec<String> = Vec::new();
+        // whatever is left after a fill can be closed again right away
+        self.closing.remove(&trade.long_id);
+        self.closing.remove(&trade.short_id);
 
         match self.positions.entry(trade.long_id.clone()) {
             Entry::Occupied(mut entry) => {
@@ -115,6 +177,7 @@ impl PositionTracker {
                 if new_size.is_zero() {
                     positions_to_remove.push(trade.long_id.clone());
                 } else {
+                    clear_brackets_on_flip(position, new_size);
                     let new_entry_price = (position.entry_price * position.size
                         + trade.amount * trade.price)
                         / new_size;
@@ -133,10 +196,13 @@ impl PositionTracker {
             Entry::Vacant(entry) => {
                 entry.insert(Position {
                     user_id: trade.long_id.clone(),
+                    symbol: self.symbol.clone(),
                     entry_price: trade.price,
                     size: trade.amount,
                     margin: adjust_for_leverage(trade.price * trade.amount, trade.long_leverage),
                     unrealized_pnl: dec!(0),
+                    take_profit: None,
+                    stop_loss: None,
                 });
             }
         }
@@ -145,15 +211,16 @@ impl PositionTracker {
             Entry::Occupied(mut entry) => {
                 let position = entry.get_mut();
 
-                let new_size = trade.amount + position.size;
+                let new_size = position.size - trade.amount;
                 if new_size.is_zero() {
-                    positions_to_remove.push(trade.long_id.clone());
+                    positions_to_remove.push(trade.short_id.clone());
                 } else {
+                    clear_brackets_on_flip(position, new_size);
                     let new_entry_price = (position.entry_price * position.size
-                        + trade.amount * trade.price)
+                        - trade.amount * trade.price)
                         / new_size;
                     position.entry_price = new_entry_price;
-                    position.size += trade.amount;
+                    position.size -= trade.amount;
                 }
 
                 // new trade going in the same direction, i.e trade_1: short, trade_2: short
@@ -168,10 +235,13 @@ impl PositionTracker {
             Entry::Vacant(entry) => {
                 entry.insert(Position {
                     user_id: trade.short_id.clone(),
+                    symbol: self.symbol.clone(),
                     entry_price: trade.price,
                     size: -trade.amount,
                     margin: adjust_for_leverage(trade.price * trade.amount, trade.short_leverage),
                     unrealized_pnl: dec!(0),
+                    take_profit: None,
+                    stop_loss: None,
                 });
             }
         }
@@ -181,6 +251,18 @@ impl PositionTracker {
         }
     }
     async fn liquidate(&mut self, user_id: &str) {
+        self.close_position(user_id).await;
+    }
+
+    // Sends a market order for the full size of the position, unless one is already on its way.
+    // The brackets stay until the position is gone, a close that only partly fills or waits out a
+    // halt leaves the rest of the position protected.
+    async fn close_position(&mut self, user_id: &str) {
+        let now = now_secs();
+        if self.is_closing(user_id, now) {
+            return;
+        }
+
         if let Some(position) = self.positions.get(user_id) {
             let size = position.size;
             if size == dec!(0) {
@@ -195,6 +277,8 @@ impl PositionTracker {
                 return;
             }
 
+            self.closing.insert(user_id.to_string(), now);
+
             if let Err(error) = self
                 .book_liquidation_tx
                 .send(OrderBookMessage::Order(order))
@@ -205,6 +289,61 @@ impl PositionTracker {
         }
     }
 
+    fn is_closing(&self, user_id: &str, now: u64) -> bool {
+        self.closing
+            .get(user_id)
+            .is_some_and(|&sent_at| now < sent_at + CLOSE_RETRY)
+    }
+
+    pub fn set_brackets(&mut self, user_id: &str, brackets: Brackets) -> Result<(), String> {
+        let position = self
+            .positions
+            .get_mut(user_id)
+            .ok_or_else(|| format!("no open {} position for {}", self.symbol, user_id))?;
+
+        // only the levels given are replaced, a take-profit alone keeps the existing stop-loss
+        position.take_profit = brackets.take_profit.or(position.take_profit);
+        position.stop_loss = brackets.stop_loss.or(position.stop_loss);
+        Ok(())
+    }
+
+    // Closes every position whose take-profit or stop-loss the current mark price has reached.
+    pub async fn trigger_brackets(&mut self) {
+        let mark_price = self.mark_price;
+        let now = now_secs();
+        let mut positions_to_close: Vec<String> = Vec::new();
+
+        for position in self.positions.values() {
+            if self.is_closing(&position.user_id, now) {
+                continue;
+            }
+            let is_long = position.size > dec!(0);
+            let take_profit_hit = position.take_profit.is_some_and(|take_profit| {
+                if is_long {
+                    mark_price >= take_profit
+                } else {
+                    mark_price <= take_profit
+                }
+            });
+            let stop_loss_hit = position.stop_loss.is_some_and(|stop_loss| {
+                if is_long {
+                    mark_price <= stop_loss
+                } else {
+                    mark_price >= stop_loss
+                }
+            });
+
+            if take_profit_hit || stop_loss_hit {
+                positions_to_close.push(position.user_id.clone());
+            }
+        }
+
+        for user_id in &positions_to_close {
+            println!("[BRACKET] closing {} @ mark {}", user_id, mark_price);
+            self.close_position(user_id).await;
+        }
+    }
+
     pub fn update_funding_rate(&mut self, index_price: Decimal) {
         let dampning_factor = dec!(0.05);
         let funding_rate_window_duration = 60; // minutes
@@ -225,8 +364,22 @@ impl PositionTracker {
         self.funding_rate_window.push(current_funding_rate);
     }
 
-    pub fn update_mark_price(&mut self, index_price: Decimal) {
+    // Recomputes the mark price and hands it to the book thread so resting stops can trigger, the
+    // index goes along for the book's price bands.
+    pub async fn update_mark_price(&mut self, index_price: Decimal) {
         self.mark_price = index_price * (dec!(1) + self.current_funding_rate);
+
+        if let Err(error) = self
+            .book_liquidation_tx
+            .send(OrderBookMessage::MarkPrice {
+                symbol: self.symbol.clone(),
+                mark_price: self.mark_price,
+                index_price,
+            })
+            .await
+        {
+            eprintln!("send mark price: {}", error);
+        }
     }
 
     // Update P&L and process liquidation if any
@@ -263,7 +416,7 @@ impl PositionTracker {
                                     * position.1.size,
                             }));
 
-                        if let Err(_) = sent {
+                        if sent.is_err() {
                             println!("[POSITION WALLET EVENT SEND ERROR]");
                         }
                     } else {
@@ -279,7 +432,7 @@ impl PositionTracker {
                             oneshot_reply: Some(oneshot_tx),
                         }));
 
-                        if let Err(_) = sent {
+                        if sent.is_err() {
                             println!("[POSITION WALLET EVENT SEND ERROR]");
                         }
 
@@ -310,7 +463,7 @@ impl PositionTracker {
                                     * position.1.size,
                             }));
 
-                        if let Err(_) = sent {
+                        if sent.is_err() {
                             println!("[POSITION WALLET EVENT SEND ERROR]");
                         }
                     } else {
@@ -326,7 +479,7 @@ impl PositionTracker {
                             oneshot_reply: Some(oneshot_tx),
                         }));
 
-                        if let Err(_) = sent {
+                        if sent.is_err() {
                             println!("[POSITION WALLET EVENT SEND ERROR]");
                         }
 
@@ -349,10 +502,12 @@ impl PositionTracker {
     }
 }
 
+// `trackers` holds one PositionTracker per listed symbol, oracle prices and trades are routed to
+// the tracker of their symbol.
 pub async fn run_position_loop(
-    mut oracle_rx: UnboundedReceiver<BtcPrice>,
+    mut oracle_rx: UnboundedReceiver<IndexPrice>,
     mut position_rx: mpsc::UnboundedReceiver<EngineEvent>,
-    mut positions: PositionTracker,
+    mut trackers: HashMap<String, PositionTracker>,
     sockets: Arc<Mutex<SocketList>>,
 ) {
     loop {
@@ -360,9 +515,13 @@ pub async fn run_position_loop(
             maybe_oracle_event = oracle_rx.recv() => {
                 match maybe_oracle_event {
                     Some(oracle_event) => {
+                        let Some(positions) = trackers.get_mut(&oracle_event.symbol) else {
+                            continue;
+                        };
                         positions.update_risk().await;
                         positions.update_funding_rate(oracle_event.price_usd);
-                        positions.update_mark_price(oracle_event.price_usd);
+                        positions.update_mark_price(oracle_event.price_usd).await;
+                        positions.trigger_brackets().await;
                     }
                     None => {
                         break;
@@ -374,11 +533,28 @@ pub async fn run_position_loop(
                     Some(event) => {
                         match event {
                             EngineEvent::Trade(trade) => {
-                                positions.last_traded_price = trade.price;
-                                positions.update_position(&trade);
+                                if let Some(positions) = trackers.get_mut(&trade.symbol) {
+                                    positions.last_traded_price = trade.price;
+                                    positions.update_position(&trade);
+                                }
                                 broadcast_trade(trade.clone(), sockets.clone()).await;
                             }
-                            EngineEvent::FundingRatePayment(msg) => positions.make_funding_payments(msg.side).await
+                            EngineEvent::FundingRatePayment(msg) => {
+                                if let Some(positions) = trackers.get_mut(&msg.symbol) {
+                                    positions.make_funding_payments(msg.side).await;
+                                }
+                            }
+                            EngineEvent::SetBrackets(msg) => {
+                                let result = match trackers.get_mut(&msg.symbol) {
+                                    Some(positions) => positions.set_brackets(&msg.user_id, msg.brackets),
+                                    None => Err(format!("unknown symbol {}", msg.symbol)),
+                                };
+                                if let Some(responder) = msg.responder {
+                                    let _ = responder.send(result);
+                                } else if let Err(error) = result {
+                                    eprintln!("[BRACKETS] {}", error);
+                                }
+                            }
                         }
                     }
                     None => {
@@ -0,0 +1,50 @@
+use std::collections::VecDeque;
+
+use crate::domain::order::Price;
+
+// Trades inside the circuit breaker window, kept as the lowest and highest price still in it
+// rather than every trade. `lows` holds rising prices and `highs` falling ones, a trade drops
+// the entries it outlives and beats on the way in, so the front of each is the window's extreme
+// and the back of both is the latest trade.
+#[derive(Default)]
+pub struct TradeWindow {
+    // (unix seconds, price), oldest first
+    lows: VecDeque<(u64, Price)>,
+    highs: VecDeque<(u64, Price)>,
+}
+
+impl TradeWindow {
+    pub fn push(&mut self, at: u64, price: Price) {
+        while self.lows.back().is_some_and(|&(_, low)| low >= price) {
+            self.lows.pop_back();
+        }
+        while self.highs.back().is_some_and(|&(_, high)| high <= price) {
+            self.highs.pop_back();
+        }
+        self.lows.push_back((at, price));
+        self.highs.push_back((at, price));
+    }
+
+    // Forgets trades made before `window_start`.
+    pub fn prune(&mut self, window_start: u64) {
+        for trades in [&mut self.lows, &mut self.highs] {
+            while trades.front().is_some_and(|&(at, _)| at < window_start) {
+                trades.pop_front();
+            }
+        }
+    }
+
+    pub fn last(&self) -> Option<Price> {
+        self.lows.back().map(|&(_, price)| price)
+    }
+
+    // (lowest, highest) price traded inside the window
+    pub fn range(&self) -> Option<(Price, Price)> {
+        Some((self.lows.front()?.1, self.highs.front()?.1))
+    }
+
+    pub fn clear(&mut self) {
+        self.lows.clear();
+        self.highs.clear();
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::domain::order::{Order, OrderType, Price, Side};
+
+// Conditional orders waiting for the mark price, kept out of the bids/asks until they fire.
+// Buy stops fire once the mark rises to their trigger, sell stops once it falls to it.
+pub struct TriggerBook {
+    buy_stops: BTreeMap<Price, Vec<Order>>,
+    sell_stops: BTreeMap<Price, Vec<Order>>,
+
+    // stop order id -> (side, trigger price)
+    orders: HashMap<String, (Side, Price)>,
+
+    // trailing stops move their trigger on every mark, so they are kept apart in arrival order
+    trailing: Vec<TrailingStop>,
+    last_mark_price: Option<Price>,
+}
+
+struct TrailingStop {
+    order: Order,
+    // best mark since the order was placed: the highest for sells, the lowest for buys
+    reference: Price,
+}
+
+impl TrailingStop {
+    // Follows the mark in the favourable direction only and moves the trigger with it.
+    fn ratchet(&mut self, mark_price: Price) {
+        self.reference = match self.order.side {
+            Side::BID => self.reference.min(mark_price),
+            Side::ASK => self.reference.max(mark_price),
+        };
+        if let Some(offset) = self.order.trailing_offset {
+            self.order.trigger_price = Some(offset.trigger_from(self.order.side, self.reference));
+        }
+    }
+
+    fn is_triggered(&self, mark_price: Price) -> bool {
+        match (self.order.side, self.order.trigger_price) {
+            (Side::BID, Some(trigger_price)) => mark_price >= trigger_price,
+            (Side::ASK, Some(trigger_price)) => mark_price <= trigger_price,
+            (_, None) => false,
+        }
+    }
+}
+
+impl TriggerBook {
+    pub fn new() -> Self {
+        TriggerBook {
+            buy_stops: BTreeMap::new(),
+            sell_stops: BTreeMap::new(),
+            orders: HashMap::new(),
+            trailing: Vec::new(),
+            last_mark_price: None,
+        }
+    }
+
+    // Starts trailing from the last mark price seen, returns the trigger level it starts at.
+    // Without a mark price yet, the order starts trailing with the first one.
+    pub fn insert_trailing(&mut self, order: Order) -> Option<Price> {
+        let mut stop = TrailingStop {
+            reference: self.last_mark_price.unwrap_or_default(),
+            order,
+        };
+        if let Some(mark_price) = self.last_mark_price {
+            stop.ratchet(mark_price);
+        }
+
+        let trigger_price = stop.order.trigger_price;
+        self.trailing.push(stop);
+        trigger_price
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Order> {
+        let Some((side, trigger_price)) = self.orders.get(id) else {
+            return self
+                .trailing
+                .iter()
+                .find(|stop| stop.order.id == id)
+                .map(|stop| &stop.order);
+        };
+        let stops = match side {
+            Side::BID => &self.buy_stops,
+            Side::ASK => &self.sell_stops,
+        };
+
+        stops
+            .get(trigger_price)?
+            .iter()
+            .find(|order| order.id == id)
+    }
+
+    pub fn insert(&mut self, order: Order, trigger_price: Price) {
+        self.orders
+            .insert(order.id.clone(), (order.side, trigger_price));
+
+        let stops = match order.side {
+            Side::BID => &mut self.buy_stops,
+            Side::ASK => &mut self.sell_stops,
+        };
+        stops.entry(trigger_price).or_default().push(order);
+    }
+
+    pub fn owner(&self, id: &str) -> Option<&str> {
+        self.get(id).map(|order| order.user_id.as_str())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Order> {
+        let Some((side, trigger_price)) = self.orders.remove(id) else {
+            let index = self.trailing.iter().position(|stop| stop.order.id == id)?;
+            return Some(self.trailing.remove(index).order);
+        };
+        let stops = match side {
+            Side::BID => &mut self.buy_stops,
+            Side::ASK => &mut self.sell_stops,
+        };
+
+        let level = stops.get_mut(&trigger_price)?;
+        let index = level.iter().position(|order| order.id == id)?;
+        let order = level.remove(index);
+        if level.is_empty() {
+            stops.remove(&trigger_price);
+        }
+
+        Some(order)
+    }
+
+    // Takes out every stop the new mark price has crossed, already converted into the market or
+    // limit order it should enter the book as. Orders at the same trigger keep arrival order,
+    // trailing stops come after the fixed ones.
+    pub fn triggered(&mut self, mark_price: Price) -> Vec<Order> {
+        if self.last_mark_price.is_none() {
+            for stop in self.trailing.iter_mut() {
+                stop.reference = mark_price;
+            }
+        }
+        self.last_mark_price = Some(mark_price);
+
+        let buy_prices: Vec<Price> = self
+            .buy_stops
+            .range(..=mark_price)
+            .map(|(&p, _)| p)
+            .collect();
+        let sell_prices: Vec<Price> = self
+            .sell_stops
+            .range(mark_price..)
+            .map(|(&p, _)| p)
+            .collect();
+
+        let mut fired = Vec::new();
+        for price in buy_prices {
+            fired.extend(self.buy_stops.remove(&price).unwrap_or_default());
+        }
+        for price in sell_prices.into_iter().rev() {
+            fired.extend(self.sell_stops.remove(&price).unwrap_or_default());
+        }
+
+        let mut still_trailing = Vec::with_capacity(self.trailing.len());
+        for mut stop in std::mem::take(&mut self.trailing) {
+            stop.ratchet(mark_price);
+            if stop.is_triggered(mark_price) {
+                fired.push(stop.order);
+            } else {
+                still_trailing.push(stop);
+            }
+        }
+        self.trailing = still_trailing;
+
+        for order in fired.iter_mut() {
+            self.orders.remove(&order.id);
+            order.order_type = match order.order_type {
+                OrderType::STOP_LIMIT => OrderType::LIMIT,
+                _ => OrderType::MARKET,
+            };
+        }
+
+        fired
+    }
+}
@@ -3,8 +3,16 @@ use std::collections::VecDeque;
 use chrono::{Duration, Timelike, Utc};
 use rust_decimal::{prelude::FromPrimitive, Decimal};
 use rust_decimal_macros::dec;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::sleep;
 
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 pub fn EMA(p: Decimal, previous_ema: Decimal, alpha: Decimal) -> Decimal {
     alpha * p + (dec!(1) - alpha) * previous_ema
 }
@@ -34,7 +42,7 @@ pub async fn scheduler() {
         let mut next = now.date_naive().and_hms_opt(next_hour % 24, 0, 0).unwrap();
         if next_hour == 24 {
             // shift to next day at 0:00
-            next = next + Duration::days(1);
+            next += Duration::days(1);
         }
 
         let sleep_duration = (next - now.naive_utc()).to_std().unwrap();
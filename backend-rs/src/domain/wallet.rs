@@ -1,13 +1,26 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::domain::wallet;
+// collects trading fees and pays out rebates
+pub const EXCHANGE_WALLET: &str = "exchange";
+
+// What a wallet holds the first time it is seen.
+fn initial_balance(wallet_id: &str) -> Decimal {
+    match wallet_id {
+        EXCHANGE_WALLET => dec!(10_000_000),
+        _ => dec!(1_000_000),
+    }
+}
 
 pub struct WalletOneshotReply {
+    #[allow(dead_code)]
     pub success: bool,
+    #[allow(dead_code)]
     pub message: String,
 }
 
@@ -23,63 +36,181 @@ pub struct WalletCreditMessage {
     pub amount: Decimal,
 }
 
+// Funds the book thread already took or gave back in its own balance copy, the wallet applies
+// these without checking.
+pub struct WalletReservationMessage {
+    pub wallet_id: String,
+    pub amount: Decimal,
+}
+
 pub enum WalletEvent {
     Debit(WalletDebitMessage),
     Credit(WalletCreditMessage),
+    Reserve(WalletReservationMessage),
+    Release(WalletReservationMessage),
 }
 
+// A balance change the book thread didn't make itself (margin, pnl, funding), sent back so its
+// copy of the balance stays in line with the wallet's.
+pub struct BalanceUpdate {
+    pub wallet_id: String,
+    pub change: Decimal,
+}
+
+// The book thread's copy of every available balance. Orders reserve funds against it without
+// a round trip to the wallet thread, which is told about the reservation afterwards and sends
+// its own changes back as `BalanceUpdate`s. Shared by all books on the book thread.
+#[derive(Clone)]
+pub struct AvailableBalances {
+    balances: Rc<RefCell<HashMap<String, Decimal>>>,
+    wallet_tx: mpsc::UnboundedSender<WalletEvent>,
+}
+
+impl AvailableBalances {
+    pub fn new(wallet_tx: mpsc::UnboundedSender<WalletEvent>) -> Self {
+        AvailableBalances {
+            balances: Rc::new(RefCell::new(HashMap::new())),
+            wallet_tx,
+        }
+    }
+
+    // Takes `amount` out of the available balance, returns false if there isn't enough.
+    pub fn reserve(&self, wallet_id: &str, amount: Decimal) -> bool {
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances
+            .entry(wallet_id.to_string())
+            .or_insert_with(|| initial_balance(wallet_id));
+        if *balance < amount {
+            return false;
+        }
+        *balance -= amount;
+
+        self.send(WalletEvent::Reserve(WalletReservationMessage {
+            wallet_id: wallet_id.to_string(),
+            amount,
+        }));
+        true
+    }
+
+    pub fn release(&self, wallet_id: &str, amount: Decimal) {
+        *self
+            .balances
+            .borrow_mut()
+            .entry(wallet_id.to_string())
+            .or_insert_with(|| initial_balance(wallet_id)) += amount;
+
+        self.send(WalletEvent::Release(WalletReservationMessage {
+            wallet_id: wallet_id.to_string(),
+            amount,
+        }));
+    }
+
+    // Moves a trading fee from `wallet_id` to the exchange, or a rebate the other way when the
+    // fee is negative. Taken even if it leaves the balance short, the fill already happened.
+    pub fn pay_fee(&self, wallet_id: &str, fee: Decimal) {
+        if fee.is_zero() {
+            return;
+        }
+        for (wallet_id, change) in [(wallet_id, -fee), (EXCHANGE_WALLET, fee)] {
+            *self
+                .balances
+                .borrow_mut()
+                .entry(wallet_id.to_string())
+                .or_insert_with(|| initial_balance(wallet_id)) += change;
+
+            let message = WalletReservationMessage {
+                wallet_id: wallet_id.to_string(),
+                amount: change.abs(),
+            };
+            self.send(if change.is_sign_negative() {
+                WalletEvent::Reserve(message)
+            } else {
+                WalletEvent::Release(message)
+            });
+        }
+    }
+
+    pub fn reconcile(&self, update: BalanceUpdate) {
+        *self
+            .balances
+            .borrow_mut()
+            .entry(update.wallet_id.clone())
+            .or_insert_with(|| initial_balance(&update.wallet_id)) += update.change;
+    }
+
+    fn send(&self, event: WalletEvent) {
+        if let Err(err) = self.wallet_tx.send(event) {
+            eprintln!("[ORDER WALLET SENDER ERROR] {}", err);
+        }
+    }
+}
+
+// Debits and credits from other threads are echoed to the book thread on `balance_tx`,
+// reservations and releases came from there and aren't.
 pub struct WalletManager {
     balance_map: HashMap<String, Decimal>,
+    balance_tx: mpsc::UnboundedSender<BalanceUpdate>,
 }
 
 impl WalletManager {
-    pub fn new() -> Self {
-        let mut balance_map = HashMap::new();
-        balance_map.insert("exchange".to_string(), dec!(10_000_000));
+    pub fn new(balance_tx: mpsc::UnboundedSender<BalanceUpdate>) -> Self {
         WalletManager {
-            balance_map: balance_map,
+            balance_map: HashMap::new(),
+            balance_tx,
         }
     }
 
+    fn balance(&mut self, wallet_id: &str) -> &mut Decimal {
+        self.balance_map
+            .entry(wallet_id.to_string())
+            .or_insert_with(|| initial_balance(wallet_id))
+    }
+
     pub fn debit(&mut self, wallet_id: String, amount: Decimal) -> bool {
         println!("[DEBITING] wallet_id: {} amount: {}", wallet_id, amount);
-        match self.balance_map.get(&wallet_id) {
-            Some(balance) => {
-                if *balance < amount {
-                    return false;
-                }
-            }
-            None => {
-                let initial_balance = dec!(1_000_000);
-                self.balance_map.insert(wallet_id.clone(), initial_balance);
-                if initial_balance < amount {
-                    return false;
-                }
-            }
+        let balance = self.balance(&wallet_id);
+        if *balance < amount {
+            return false;
         }
+        *balance -= amount;
 
-        self.balance_map
-            .entry(wallet_id)
-            .and_modify(|b| *b -= amount)
-            .or_insert(dec!(1_000_000));
-
+        self.echo(wallet_id, -amount);
         true
     }
 
     pub fn credit(&mut self, wallet_id: String, amount: Decimal) {
-        self.balance_map
-            .entry(wallet_id)
-            .and_modify(|b| *b += amount);
-        // no or_insert here, cuz not possible
+        *self.balance(&wallet_id) += amount;
+        self.echo(wallet_id, amount);
     }
 
-    pub fn transfer(&mut self, payment_sender_id: String, payment_reciever_id: String) {}
+    // The book checked the reservation against its own copy, which can be a debit behind the
+    // wallet while a `BalanceUpdate` is in flight, so this may briefly overdraw.
+    pub fn reserve(&mut self, wallet_id: String, amount: Decimal) {
+        let balance = self.balance(&wallet_id);
+        *balance -= amount;
+        if balance.is_sign_negative() {
+            eprintln!(
+                "[WALLET RECONCILE] {} overdrawn to {} by a reservation",
+                wallet_id, balance
+            );
+        }
+    }
 
-    pub fn get_balance(self, wallet_id: String) -> Option<String> {
-        if let Some(b) = self.balance_map.get(&wallet_id) {
-            Some(b.to_string())
-        } else {
-            None
+    pub fn release(&mut self, wallet_id: String, amount: Decimal) {
+        *self.balance(&wallet_id) += amount;
+    }
+
+    fn echo(&self, wallet_id: String, change: Decimal) {
+        if let Err(err) = self.balance_tx.send(BalanceUpdate { wallet_id, change }) {
+            eprintln!("[WALLET BALANCE SENDER ERROR] {}", err);
         }
     }
+
+    #[allow(dead_code)]
+    pub fn transfer(&mut self, _payment_sender_id: String, _payment_reciever_id: String) {}
+
+    #[allow(dead_code)]
+    pub fn get_balance(self, wallet_id: String) -> Option<String> {
+        self.balance_map.get(&wallet_id).map(|b| b.to_string())
+    }
 }
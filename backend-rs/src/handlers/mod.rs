@@ -1,11 +1,31 @@
 pub mod order;
+pub mod position;
 pub mod websocket;
 
-pub use order::order_handler;
-pub use websocket::{broadcast_trade, ws_handler};
+pub use order::{
+    amend_by_client_id_handler, amend_handler, cancel_by_client_id_handler, cancel_handler,
+    dead_man_switch_handler, mass_cancel_handler, oco_handler, order_handler,
+    order_status_by_client_id_handler, order_status_handler, orders_handler,
+    trigger_level_by_client_id_handler, trigger_level_handler,
+};
+pub use position::brackets_handler;
+pub use websocket::{broadcast_market_data, broadcast_trade, ws_handler};
 
-use crate::types::Response;
-use axum::response::Json;
+use crate::domain::fee_tier::FeeTierQuery;
+use crate::domain::instrument::{instrument, Instrument, INSTRUMENTS};
+use crate::domain::market_data::{DepthQuery, L3Query};
+use crate::domain::order::{MaintenanceQuery, MarketStatusQuery};
+use crate::state::BookState;
+use crate::types::{
+    DepthRequest, FeeTierRequest, L3Request, MaintenanceRequest, OrderBookMessage, Response,
+};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::oneshot;
 
 pub async fn handler() -> Json<Response> {
     Json(Response {
@@ -13,3 +33,183 @@ pub async fn handler() -> Json<Response> {
         error: "".to_string(),
     })
 }
+
+// Contract specs of every listed instrument.
+pub async fn instruments_handler() -> Json<&'static [Instrument]> {
+    Json(INSTRUMENTS)
+}
+
+// Trading state of one instrument: whether the circuit breaker has it halted, and its prices.
+pub async fn market_status_handler(
+    State(state): State<BookState>,
+    Path(symbol): Path<String>,
+) -> axum::response::Response {
+    if instrument(&symbol).is_none() {
+        return unknown_symbol(&symbol);
+    }
+
+    query_book(&state, |responder| {
+        OrderBookMessage::MarketStatus(MarketStatusQuery {
+            symbol,
+            responder: Some(responder),
+        })
+    })
+    .await
+}
+
+// longest maintenance window an admin can put an instrument into in one go
+const MAX_MAINTENANCE_DURATION: u64 = 24 * 60 * 60;
+
+// Halts an instrument for maintenance, it re-opens through an auction once the duration is up.
+// Admins only: the request has to carry the ADMIN_TOKEN the server was started with, without
+// one set the endpoint is disabled.
+pub async fn maintenance_handler(
+    State(state): State<BookState>,
+    Path(symbol): Path<String>,
+    Json(payload): Json<MaintenanceRequest>,
+) -> axum::response::Response {
+    if std::env::var("ADMIN_TOKEN").map_or(true, |token| {
+        token.is_empty() || token != payload.admin_token
+    }) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(Response {
+                message: String::new(),
+                error: "Maintenance needs a valid admin token".to_string(),
+            }),
+        )
+            .into_response();
+    }
+    if payload.duration == 0 || payload.duration > MAX_MAINTENANCE_DURATION {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(Response {
+                message: String::new(),
+                error: format!(
+                    "Maintenance duration must be 1 to {} seconds, got {}",
+                    MAX_MAINTENANCE_DURATION, payload.duration
+                ),
+            }),
+        )
+            .into_response();
+    }
+
+    if instrument(&symbol).is_none() {
+        return unknown_symbol(&symbol);
+    }
+
+    query_book(&state, |responder| {
+        OrderBookMessage::Maintenance(MaintenanceQuery {
+            symbol,
+            duration: payload.duration,
+            responder: Some(responder),
+        })
+    })
+    .await
+}
+
+// Snapshot of the top `levels` price levels per side. Its sequence number tells depth feed
+// subscribers which incremental updates are already included.
+pub async fn depth_handler(
+    State(state): State<BookState>,
+    Query(payload): Query<DepthRequest>,
+) -> axum::response::Response {
+    if instrument(&payload.symbol).is_none() {
+        return unknown_symbol(&payload.symbol);
+    }
+
+    query_book(&state, |responder| {
+        OrderBookMessage::Depth(DepthQuery {
+            symbol: payload.symbol,
+            levels: payload.levels.unwrap_or(10),
+            responder: Some(responder),
+        })
+    })
+    .await
+}
+
+// Every resting order of one instrument in priority order, the starting point for the l3 feed.
+pub async fn l3_handler(
+    State(state): State<BookState>,
+    Query(payload): Query<L3Request>,
+) -> axum::response::Response {
+    if instrument(&payload.symbol).is_none() {
+        return unknown_symbol(&payload.symbol);
+    }
+
+    query_book(&state, |responder| {
+        OrderBookMessage::L3(L3Query {
+            symbol: payload.symbol,
+            responder: Some(responder),
+        })
+    })
+    .await
+}
+
+// The caller's VIP fee tier and the trailing volume it was worked out from.
+pub async fn fee_tier_handler(
+    State(state): State<BookState>,
+    Query(payload): Query<FeeTierRequest>,
+) -> axum::response::Response {
+    query_book(&state, |responder| {
+        OrderBookMessage::FeeTier(FeeTierQuery {
+            user_id: payload.jwt,
+            responder: Some(responder),
+        })
+    })
+    .await
+}
+
+fn unknown_symbol(symbol: &str) -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(Response {
+            message: String::new(),
+            error: format!("Unknown symbol: {}", symbol),
+        }),
+    )
+        .into_response()
+}
+
+// Sends the book thread the query `message` builds around a fresh responder and answers with
+// whatever comes back on it, as json.
+async fn query_book<T: Serialize>(
+    state: &BookState,
+    message: impl FnOnce(oneshot::Sender<T>) -> OrderBookMessage,
+) -> axum::response::Response {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(e) = state.tx.send(message(resp_tx)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send query to processing thread: {}", e),
+            }),
+        )
+            .into_response();
+    }
+
+    match resp_rx.await {
+        Ok(answer) => Json(answer).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Query was dropped before response: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// Converts an optional f64 from a request body, failing only when a value was sent but can't be
+// represented as a Decimal (NaN, infinity).
+pub fn optional_decimal(value: Option<f64>) -> Result<Option<Decimal>, String> {
+    match value {
+        Some(v) => Decimal::from_f64(v)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid number: {}", v)),
+        None => Ok(None),
+    }
+}
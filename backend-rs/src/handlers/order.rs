@@ -1,12 +1,29 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, response::Json};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    response::Json,
+};
 use rust_decimal::prelude::FromPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use uuid::Uuid;
 
+use crate::domain::instrument::instrument;
+use crate::domain::order::{
+    AmendOrder, BookError, CancelOrder, DeadManSwitch, MassCancel, TriggerLevelQuery,
+};
+use crate::domain::order::{PostOnly, SelfTradePrevention, TimeInForce, TrailingOffset};
+use crate::domain::order_history::{OrderStatusQuery, OrdersQuery};
+use crate::domain::position::Brackets;
 use crate::domain::{Order, OrderType, Side};
+use crate::handlers::optional_decimal;
 use crate::state::BookState;
-use crate::types::{OrderBookMessage, OrderRequest, Response};
+use crate::types::{
+    AmendRequest, CancelRequest, DeadManSwitchRequest, MassCancelRequest, OcoRequest,
+    OrderBookMessage, OrderRequest, OrderStatusRequest, OrdersRequest, Response,
+    TriggerLevelRequest,
+};
 
 pub async fn order_handler(
     State(state): State<BookState>,
@@ -14,32 +31,178 @@ pub async fn order_handler(
 ) -> impl IntoResponse {
     let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
 
+    let mut order = match parse_order(payload) {
+        Ok(order) => order,
+        Err(error) => return bad_request(error),
+    };
+    order.responder = Some(resp_tx);
+
+    if let Err(e) = state.tx.send(OrderBookMessage::Order(order)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send order to processing thread: {}", e),
+            }),
+        );
+    }
+
+    match resp_rx.await {
+        Ok(response) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!(
+                    "Order {} processed: filled {}, remaining {}, fee {}, {}",
+                    response.order_id,
+                    response.filled,
+                    response.remaining,
+                    response.fee,
+                    response.status
+                ),
+                error: String::new(),
+            }),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Order was dropped before response: {}", e),
+            }),
+        ),
+    }
+}
+
+// Places two orders where a fill or trigger of either one cancels the other.
+pub async fn oco_handler(
+    State(state): State<BookState>,
+    Json(payload): Json<OcoRequest>,
+) -> impl IntoResponse {
+    let (first_tx, first_rx) = tokio::sync::oneshot::channel();
+    let (second_tx, second_rx) = tokio::sync::oneshot::channel();
+
+    if payload.first.jwt != payload.second.jwt {
+        return bad_request("Both legs of an oco order must belong to the same user".to_string());
+    }
+    if payload.first.symbol != payload.second.symbol {
+        return bad_request("Both legs of an oco order must be on the same symbol".to_string());
+    }
+    if payload.first.idempotency_key.is_some() || payload.second.idempotency_key.is_some() {
+        return bad_request("Oco orders do not take an idempotency key".to_string());
+    }
+    if payload.first.client_order_id.is_some()
+        && payload.first.client_order_id == payload.second.client_order_id
+    {
+        return bad_request("Both legs of an oco order need their own client order id".to_string());
+    }
+
+    let (mut first, mut second) = match (parse_order(payload.first), parse_order(payload.second)) {
+        (Ok(first), Ok(second)) => (first, second),
+        (Err(error), _) | (_, Err(error)) => return bad_request(error),
+    };
+
+    for leg in [&first, &second] {
+        let rests = matches!(leg.order_type, OrderType::LIMIT)
+            && matches!(leg.time_in_force, TimeInForce::GTC | TimeInForce::GTD(_));
+        let is_stop = matches!(
+            leg.order_type,
+            OrderType::STOP_MARKET | OrderType::STOP_LIMIT | OrderType::TRAILING_STOP
+        );
+        if !rests && !is_stop {
+            return bad_request(
+                "Oco legs must be resting limit orders (gtc/gtd) or stop orders".to_string(),
+            );
+        }
+    }
+
+    first.responder = Some(first_tx);
+    second.responder = Some(second_tx);
+
+    if let Err(e) = state
+        .tx
+        .send(OrderBookMessage::Oco(Box::new((first, second))))
+        .await
+    {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send oco order to processing thread: {}", e),
+            }),
+        );
+    }
+
+    match (first_rx.await, second_rx.await) {
+        (Ok(first), Ok(second)) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!(
+                    "Order {} processed: filled {}, remaining {}, fee {}, {} | Order {} processed: filled {}, remaining {}, fee {}, {}",
+                    first.order_id,
+                    first.filled,
+                    first.remaining,
+                    first.fee,
+                    first.status,
+                    second.order_id,
+                    second.filled,
+                    second.remaining,
+                    second.fee,
+                    second.status
+                ),
+                error: String::new(),
+            }),
+        ),
+        (Err(e), _) | (_, Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Oco order was dropped before response: {}", e),
+            }),
+        ),
+    }
+}
+
+// Turns a request body into a validated Order, errors are meant to be shown to the client as is.
+fn parse_order(payload: OrderRequest) -> Result<Order, String> {
+    let Some(instrument) = instrument(&payload.symbol) else {
+        return Err(format!("Unknown symbol: {}", payload.symbol));
+    };
+
     let type_ = match payload.type_.as_str() {
         "limit" => OrderType::LIMIT,
         "market" => OrderType::MARKET,
-        other => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(Response {
-                    message: String::new(),
-                    error: format!("Invalid order type: {}", other),
-                }),
-            );
-        }
+        "stop_market" => OrderType::STOP_MARKET,
+        "stop_limit" => OrderType::STOP_LIMIT,
+        "trailing_stop" => OrderType::TRAILING_STOP,
+        other => return Err(format!("Invalid order type: {}", other)),
     };
 
     let side = match payload.side.as_str() {
         "buy" => Side::BID,
         "sell" => Side::ASK,
-        other => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(Response {
-                    message: String::new(),
-                    error: format!("Invalid side: {}", other),
-                }),
-            );
-        }
+        other => return Err(format!("Invalid side: {}", other)),
+    };
+
+    let time_in_force = match (payload.time_in_force.as_deref(), payload.expires_at) {
+        (None | Some("gtc"), _) => TimeInForce::GTC,
+        (Some("ioc"), _) => TimeInForce::IOC,
+        (Some("fok"), _) => TimeInForce::FOK,
+        (Some("gtd"), Some(expires_at)) => TimeInForce::GTD(expires_at),
+        (Some("gtd"), None) => return Err("gtd orders need expires_at (unix seconds)".to_string()),
+        (Some(other), _) => return Err(format!("Invalid time in force: {}", other)),
+    };
+
+    let self_trade_prevention = match payload.self_trade_prevention.as_deref() {
+        None => None,
+        Some("cancel_taker") => Some(SelfTradePrevention::CancelTaker),
+        Some("cancel_maker") => Some(SelfTradePrevention::CancelMaker),
+        Some("cancel_both") => Some(SelfTradePrevention::CancelBoth),
+        Some(other) => return Err(format!("Invalid self trade prevention mode: {}", other)),
+    };
+
+    let post_only = match (payload.post_only, payload.post_only_reprice) {
+        (Some(true), Some(true)) => Some(PostOnly::Reprice),
+        (Some(true), _) => Some(PostOnly::Reject),
+        _ => None,
     };
 
     let (price, amount) = match (
@@ -48,59 +211,580 @@ pub async fn order_handler(
     ) {
         (Some(p), Some(a)) => (p, a),
         _ => {
+            return Err(format!(
+                "Invalid price or amount: {} / {}",
+                payload.price, payload.amount
+            ))
+        }
+    };
+
+    let leverage = Decimal::from_u32(payload.leverage).unwrap_or(dec!(1));
+
+    let (trigger_price, take_profit, stop_loss) = match (
+        optional_decimal(payload.trigger_price),
+        optional_decimal(payload.take_profit),
+        optional_decimal(payload.stop_loss),
+    ) {
+        (Ok(trigger_price), Ok(take_profit), Ok(stop_loss)) => {
+            (trigger_price, take_profit, stop_loss)
+        }
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            return Err(format!(
+                "Invalid trigger price, take profit or stop loss: {}",
+                e
+            ))
+        }
+    };
+
+    let display_amount = optional_decimal(payload.display_amount)
+        .map_err(|e| format!("Invalid display amount: {}", e))?;
+
+    let (protection_price, max_slippage_bps) = match (
+        optional_decimal(payload.protection_price),
+        optional_decimal(payload.max_slippage_bps),
+    ) {
+        (Ok(protection_price), Ok(max_slippage_bps)) => (protection_price, max_slippage_bps),
+        (Err(e), _) | (_, Err(e)) => {
+            return Err(format!("Invalid protection price or max slippage: {}", e))
+        }
+    };
+
+    let trailing_offset = match (
+        optional_decimal(payload.trailing_offset),
+        optional_decimal(payload.trailing_percent),
+    ) {
+        (Ok(None), Ok(None)) => None,
+        (Ok(Some(offset)), Ok(None)) => Some(TrailingOffset::Absolute(offset)),
+        (Ok(None), Ok(Some(percent))) => Some(TrailingOffset::Percent(percent)),
+        (Ok(Some(_)), Ok(Some(_))) => {
+            return Err("Send either trailing_offset or trailing_percent, not both".to_string())
+        }
+        (Err(e), _) | (_, Err(e)) => return Err(format!("Invalid trailing offset: {}", e)),
+    };
+
+    let brackets = (take_profit.is_some() || stop_loss.is_some()).then_some(Brackets {
+        take_profit,
+        stop_loss,
+    });
+
+    let order = Order {
+        id: Uuid::new_v4().to_string(),
+        user_id: payload.jwt,
+        client_order_id: payload.client_order_id,
+        idempotency_key: payload.idempotency_key,
+        symbol: payload.symbol,
+        order_type: type_,
+        amount,
+        price,
+        side,
+        leverage,
+        time_in_force,
+        post_only,
+        display_amount,
+        reserve: dec!(0),
+        reduce_only: payload.reduce_only.unwrap_or(false),
+        self_trade_prevention,
+        self_trade_prevented: false,
+        fees: dec!(0),
+        repriced_from: None,
+        trigger_price,
+        trailing_offset,
+        protection_price,
+        max_slippage_bps,
+        brackets,
+        responder: None,
+    };
+
+    order
+        .validate()
+        .and_then(|_| instrument.check_order(&order))
+        .map_err(|e| format!("Invalid order: {}", e))?;
+
+    Ok(order)
+}
+
+fn bad_request(error: String) -> (StatusCode, Json<Response>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(Response {
+            message: String::new(),
+            error,
+        }),
+    )
+}
+
+pub async fn cancel_handler(
+    State(state): State<BookState>,
+    Path(id): Path<String>,
+    Json(payload): Json<CancelRequest>,
+) -> impl IntoResponse {
+    cancel(state, id, false, payload).await
+}
+
+pub async fn cancel_by_client_id_handler(
+    State(state): State<BookState>,
+    Path(client_order_id): Path<String>,
+    Json(payload): Json<CancelRequest>,
+) -> impl IntoResponse {
+    cancel(state, client_order_id, true, payload).await
+}
+
+async fn cancel(
+    state: BookState,
+    id: String,
+    by_client_id: bool,
+    payload: CancelRequest,
+) -> (StatusCode, Json<Response>) {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    let cancel = CancelOrder {
+        id,
+        user_id: payload.jwt,
+        by_client_id,
+        responder: Some(resp_tx),
+    };
+
+    if let Err(e) = state.tx.send(OrderBookMessage::Cancel(cancel)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send cancel to processing thread: {}", e),
+            }),
+        );
+    }
+
+    match resp_rx.await {
+        Ok(Ok(remaining)) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!("Order cancelled: released {}", remaining),
+                error: String::new(),
+            }),
+        ),
+        Ok(Err(error)) => book_error_response(error),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Cancel was dropped before response: {}", e),
+            }),
+        ),
+    }
+}
+
+// Cancels all of the user's open orders, or only those matching the given symbol, side and
+// price range.
+pub async fn mass_cancel_handler(
+    State(state): State<BookState>,
+    Json(payload): Json<MassCancelRequest>,
+) -> impl IntoResponse {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    if let Some(symbol) = payload.symbol.as_deref() {
+        if instrument(symbol).is_none() {
+            return bad_request(format!("Unknown symbol: {}", symbol));
+        }
+    }
+    let side = match payload.side.as_deref() {
+        None => None,
+        Some("buy") => Some(Side::BID),
+        Some("sell") => Some(Side::ASK),
+        Some(other) => return bad_request(format!("Invalid side: {}", other)),
+    };
+    let (min_price, max_price) = match (
+        optional_decimal(payload.min_price),
+        optional_decimal(payload.max_price),
+    ) {
+        (Ok(min_price), Ok(max_price)) => (min_price, max_price),
+        (Err(e), _) | (_, Err(e)) => return bad_request(format!("Invalid price range: {}", e)),
+    };
+
+    let cancel = MassCancel {
+        user_id: payload.jwt,
+        symbol: payload.symbol,
+        side,
+        min_price,
+        max_price,
+        responder: Some(resp_tx),
+    };
+
+    if let Err(e) = state.tx.send(OrderBookMessage::MassCancel(cancel)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send mass cancel to processing thread: {}", e),
+            }),
+        );
+    }
+
+    match resp_rx.await {
+        Ok(cancelled) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!(
+                    "Cancelled {} orders: {}",
+                    cancelled.len(),
+                    cancelled.join(", ")
+                ),
+                error: String::new(),
+            }),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Mass cancel was dropped before response: {}", e),
+            }),
+        ),
+    }
+}
+
+// longest a dead man's switch can be armed for, in seconds
+const MAX_DEAD_MAN_TIMEOUT: u64 = 24 * 60 * 60;
+
+// Arms or refreshes the dead man's switch, a timeout of 0 disarms it. Clients are expected to
+// call this again well before the timeout runs out for as long as they are alive.
+pub async fn dead_man_switch_handler(
+    State(state): State<BookState>,
+    Json(payload): Json<DeadManSwitchRequest>,
+) -> impl IntoResponse {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    if payload.timeout > MAX_DEAD_MAN_TIMEOUT {
+        return bad_request(format!(
+            "Timeout must be at most {} seconds, got {}",
+            MAX_DEAD_MAN_TIMEOUT, payload.timeout
+        ));
+    }
+
+    let switch = DeadManSwitch {
+        user_id: payload.jwt,
+        timeout: payload.timeout,
+        responder: Some(resp_tx),
+    };
+
+    if let Err(e) = state.tx.send(OrderBookMessage::DeadManSwitch(switch)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!(
+                    "Failed to send dead man's switch to processing thread: {}",
+                    e
+                ),
+            }),
+        );
+    }
+
+    match resp_rx.await {
+        Ok(Some(fires_at)) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!(
+                    "Dead man's switch armed, all orders get cancelled at {} unless refreshed",
+                    fires_at
+                ),
+                error: String::new(),
+            }),
+        ),
+        Ok(None) => (
+            StatusCode::OK,
+            Json(Response {
+                message: "Dead man's switch disarmed".to_string(),
+                error: String::new(),
+            }),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Dead man's switch was dropped before response: {}", e),
+            }),
+        ),
+    }
+}
+
+pub async fn amend_handler(
+    State(state): State<BookState>,
+    Path(id): Path<String>,
+    Json(payload): Json<AmendRequest>,
+) -> impl IntoResponse {
+    amend(state, id, false, payload).await
+}
+
+pub async fn amend_by_client_id_handler(
+    State(state): State<BookState>,
+    Path(client_order_id): Path<String>,
+    Json(payload): Json<AmendRequest>,
+) -> impl IntoResponse {
+    amend(state, client_order_id, true, payload).await
+}
+
+async fn amend(
+    state: BookState,
+    id: String,
+    by_client_id: bool,
+    payload: AmendRequest,
+) -> (StatusCode, Json<Response>) {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    let (amount, price) = match (
+        payload.amount.map(Decimal::from_f64),
+        payload.price.map(Decimal::from_f64),
+    ) {
+        (None, None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(Response {
+                    message: String::new(),
+                    error: "Nothing to amend, send amount and/or price".to_string(),
+                }),
+            );
+        }
+        (Some(None), _) | (_, Some(None)) => {
             return (
                 StatusCode::BAD_REQUEST,
                 Json(Response {
                     message: String::new(),
                     error: format!(
-                        "Invalid price or amount: {} / {}",
+                        "Invalid price or amount: {:?} / {:?}",
                         payload.price, payload.amount
                     ),
                 }),
             );
         }
+        (amount, price) => (amount.flatten(), price.flatten()),
     };
 
-    let leverage = Decimal::from_u32(payload.leverage).unwrap_or(dec!(1));
-
-    let order = Order {
-        id: Uuid::new_v4().to_string(),
+    let amend = AmendOrder {
+        id,
         user_id: payload.jwt,
-        order_type: type_,
+        by_client_id,
         amount,
         price,
-        side,
-        leverage,
         responder: Some(resp_tx),
     };
 
-    if let Err(e) = state.tx.send(OrderBookMessage::Order(order)).await {
+    if let Err(e) = state.tx.send(OrderBookMessage::Amend(amend)).await {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(Response {
                 message: String::new(),
-                error: format!("Failed to send order to processing thread: {}", e),
+                error: format!("Failed to send amend to processing thread: {}", e),
             }),
         );
     }
 
     match resp_rx.await {
-        Ok(response) => (
+        Ok(Ok(response)) => (
             StatusCode::OK,
             Json(Response {
                 message: format!(
-                    "Order processed: filled {}, remaining {}, {}",
-                    response.filled, response.remaining, response.status
+                    "Order amended: filled {}, remaining {}, fee {}, {}",
+                    response.filled, response.remaining, response.fee, response.status
                 ),
                 error: String::new(),
             }),
         ),
+        Ok(Err(error)) => book_error_response(error),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(Response {
                 message: String::new(),
-                error: format!("Order was dropped before response: {}", e),
+                error: format!("Amend was dropped before response: {}", e),
             }),
         ),
     }
 }
+
+// Current trigger level of a stop order, for trailing stops this is where the trail is right now.
+pub async fn trigger_level_handler(
+    State(state): State<BookState>,
+    Path(id): Path<String>,
+    Query(payload): Query<TriggerLevelRequest>,
+) -> impl IntoResponse {
+    trigger_level(state, id, false, payload).await
+}
+
+pub async fn trigger_level_by_client_id_handler(
+    State(state): State<BookState>,
+    Path(client_order_id): Path<String>,
+    Query(payload): Query<TriggerLevelRequest>,
+) -> impl IntoResponse {
+    trigger_level(state, client_order_id, true, payload).await
+}
+
+async fn trigger_level(
+    state: BookState,
+    id: String,
+    by_client_id: bool,
+    payload: TriggerLevelRequest,
+) -> (StatusCode, Json<Response>) {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    let query = TriggerLevelQuery {
+        id: id.clone(),
+        user_id: payload.jwt,
+        by_client_id,
+        responder: Some(resp_tx),
+    };
+
+    if let Err(e) = state.tx.send(OrderBookMessage::TriggerLevel(query)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send query to processing thread: {}", e),
+            }),
+        );
+    }
+
+    match resp_rx.await {
+        Ok(Ok(Some(trigger_price))) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!("Order {} triggers at mark price {}", id, trigger_price),
+                error: String::new(),
+            }),
+        ),
+        Ok(Ok(None)) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!("Order {} is waiting for the first mark price", id),
+                error: String::new(),
+            }),
+        ),
+        Ok(Err(error)) => book_error_response(error),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Query was dropped before response: {}", e),
+            }),
+        ),
+    }
+}
+
+// Where an order stands: remaining and filled amount, average fill price and state.
+pub async fn order_status_handler(
+    State(state): State<BookState>,
+    Path(id): Path<String>,
+    Query(payload): Query<OrderStatusRequest>,
+) -> axum::response::Response {
+    order_status(state, id, false, payload).await
+}
+
+pub async fn order_status_by_client_id_handler(
+    State(state): State<BookState>,
+    Path(client_order_id): Path<String>,
+    Query(payload): Query<OrderStatusRequest>,
+) -> axum::response::Response {
+    order_status(state, client_order_id, true, payload).await
+}
+
+async fn order_status(
+    state: BookState,
+    id: String,
+    by_client_id: bool,
+    payload: OrderStatusRequest,
+) -> axum::response::Response {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    let query = OrderStatusQuery {
+        id,
+        user_id: payload.jwt,
+        by_client_id,
+        responder: Some(resp_tx),
+    };
+
+    if let Err(e) = state.tx.send(OrderBookMessage::OrderStatus(query)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send query to processing thread: {}", e),
+            }),
+        )
+            .into_response();
+    }
+
+    match resp_rx.await {
+        Ok(Ok(record)) => Json(record).into_response(),
+        Ok(Err(error)) => book_error_response(error).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Query was dropped before response: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// The user's open orders across all instruments, or with status=closed the recently finished ones.
+pub async fn orders_handler(
+    State(state): State<BookState>,
+    Query(payload): Query<OrdersRequest>,
+) -> axum::response::Response {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    let open = match payload.status.as_deref() {
+        None | Some("open") => true,
+        Some("closed") => false,
+        Some(other) => {
+            return bad_request(format!("Invalid status: {}, use open or closed", other))
+                .into_response()
+        }
+    };
+
+    let query = OrdersQuery {
+        user_id: payload.jwt,
+        open,
+        responder: Some(resp_tx),
+    };
+
+    if let Err(e) = state.tx.send(OrderBookMessage::Orders(query)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send query to processing thread: {}", e),
+            }),
+        )
+            .into_response();
+    }
+
+    match resp_rx.await {
+        Ok(orders) => Json(orders).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Query was dropped before response: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+fn book_error_response(error: BookError) -> (StatusCode, Json<Response>) {
+    let status = match error {
+        BookError::OrderNotFound(_) => StatusCode::NOT_FOUND,
+        BookError::NotOrderOwner(_) => StatusCode::FORBIDDEN,
+        BookError::InvalidAmend(_) => StatusCode::BAD_REQUEST,
+        BookError::InsufficientBalance => StatusCode::PAYMENT_REQUIRED,
+        BookError::WouldTakeLiquidity(_) => StatusCode::CONFLICT,
+        BookError::MarketHalted(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        status,
+        Json(Response {
+            message: String::new(),
+            error: error.to_string(),
+        }),
+    )
+}
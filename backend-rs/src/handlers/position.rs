@@ -0,0 +1,96 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, response::Json};
+
+use crate::domain::instrument::instrument;
+use crate::domain::position::{BracketMessage, Brackets, EngineEvent};
+use crate::handlers::optional_decimal;
+use crate::state::PositionState;
+use crate::types::{BracketRequest, Response};
+
+// Sets the take-profit / stop-loss on the caller's open position, omitted prices keep their level.
+pub async fn brackets_handler(
+    State(state): State<PositionState>,
+    Json(payload): Json<BracketRequest>,
+) -> impl IntoResponse {
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+
+    if instrument(&payload.symbol).is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(Response {
+                message: String::new(),
+                error: format!("Unknown symbol: {}", payload.symbol),
+            }),
+        );
+    }
+
+    let brackets = match (
+        optional_decimal(payload.take_profit),
+        optional_decimal(payload.stop_loss),
+    ) {
+        (Ok(take_profit), Ok(stop_loss)) => Brackets {
+            take_profit,
+            stop_loss,
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(Response {
+                    message: String::new(),
+                    error: format!("Invalid take profit or stop loss: {}", e),
+                }),
+            );
+        }
+    };
+
+    if let Err(e) = brackets.validate() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(Response {
+                message: String::new(),
+                error: e,
+            }),
+        );
+    }
+
+    if let Err(e) = state.tx.send(EngineEvent::SetBrackets(BracketMessage {
+        user_id: payload.jwt,
+        symbol: payload.symbol,
+        brackets,
+        responder: Some(resp_tx),
+    })) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Failed to send brackets to position thread: {}", e),
+            }),
+        );
+    }
+
+    match resp_rx.await {
+        Ok(Ok(())) => (
+            StatusCode::OK,
+            Json(Response {
+                message: format!(
+                    "Brackets set: take profit {:?}, stop loss {:?}",
+                    brackets.take_profit, brackets.stop_loss
+                ),
+                error: String::new(),
+            }),
+        ),
+        Ok(Err(e)) => (
+            StatusCode::NOT_FOUND,
+            Json(Response {
+                message: String::new(),
+                error: e,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response {
+                message: String::new(),
+                error: format!("Brackets were dropped before response: {}", e),
+            }),
+        ),
+    }
+}
@@ -4,80 +4,190 @@ use axum::{
     response::Response as AxumResponse,
 };
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{mpsc, Mutex};
 
+use crate::domain::market_data::MarketData;
+use crate::domain::order::MassCancel;
 use crate::domain::position::Trade;
-use crate::types::{SocketMessageRecv, SocketMessageSend};
+use crate::state::SocketState;
+use crate::types::{OrderBookMessage, SocketMessageRecv, SocketMessageSend};
 
-pub type SocketList = HashMap<String, mpsc::Sender<SocketMessageSend>>;
+// One connected client. Broadcasts only go to subscribers that want them, and never wait on
+// a client: one whose channel is full gets dropped from the list, which ends its socket.
+pub struct Subscriber {
+    // empty for public market data clients that didn't send one
+    jwt: String,
+    sender: mpsc::Sender<SocketMessageSend>,
+    subscription: Subscription,
+}
+
+// Keyed by session, a user can have several sockets open at once (say trades and depth), and
+// public clients all share the empty jwt.
+pub type SocketList = HashMap<u64, Subscriber>;
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+// What a client asked for in its hello message.
+struct Subscription {
+    symbol: Option<String>,
+    channels: Vec<String>,
+    cancel_on_disconnect: bool,
+}
+
+impl Subscription {
+    fn wants(&self, channel: &str, symbol: &str) -> bool {
+        self.channels.iter().any(|c| c == channel)
+            && self.symbol.as_ref().is_none_or(|s| s == symbol)
+    }
+}
 
-pub async fn ws_handler(
-    State(sockets): State<Arc<Mutex<SocketList>>>,
-    ws: WebSocketUpgrade,
-) -> AxumResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, sockets))
+pub async fn ws_handler(State(state): State<SocketState>, ws: WebSocketUpgrade) -> AxumResponse {
+    ws.on_upgrade(|socket| handle_socket(socket, state))
 }
 
-pub async fn handle_socket(mut socket: WebSocket, sockets: Arc<Mutex<SocketList>>) {
+pub async fn handle_socket(mut socket: WebSocket, state: SocketState) {
     println!("Some ws client connected");
 
+    let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
     let (socket_tx, mut socket_rx) = mpsc::channel::<SocketMessageSend>(1000);
 
-    loop {
-        if let Ok(jwt) = handle_websocket_message(&mut socket).await {
-            let mut socket_list = sockets.lock().await;
-            socket_list.insert(jwt, socket_tx);
+    let (jwt, cancel_on_disconnect) = loop {
+        if let Ok((jwt, subscription)) = handle_websocket_message(&mut socket).await {
+            let cancel_on_disconnect = subscription.cancel_on_disconnect;
+            let mut socket_list = state.sockets.lock().await;
+            socket_list.insert(
+                session,
+                Subscriber {
+                    jwt: jwt.clone(),
+                    sender: socket_tx,
+                    subscription,
+                },
+            );
             println!("socket_list len: {}", socket_list.len());
-            break;
+            break (jwt, cancel_on_disconnect);
         }
-    }
+    };
 
     loop {
-        if let Some(msg) = socket_rx.recv().await {
-            match msg {
-                SocketMessageSend::Trade(trade) => {
-                    if let Ok(json) = serde_json::to_string(&trade) {
-                        if let Err(error) = socket.send(ws::Message::text(json)).await {
-                            eprintln!("[SOCKET ERROR]:\n{}", error);
-                        }
-                    }
-                }
+        let msg = tokio::select! {
+            msg = socket_rx.recv() => match msg {
+                Some(msg) => msg,
+                // dropped from the socket list for lagging behind
+                None => break,
+            },
+            incoming = socket.recv() => match incoming {
+                // clients only ever send their hello, anything after it is ignored
+                Some(Ok(message)) if !matches!(message, ws::Message::Close(_)) => continue,
+                _ => break,
+            },
+        };
+        let json = match msg {
+            SocketMessageSend::Trade(trade) => serde_json::to_string(&trade),
+            SocketMessageSend::MarketData(data) => serde_json::to_string(&data),
+        };
+        if let Ok(json) = json {
+            if let Err(error) = socket.send(ws::Message::text(json)).await {
+                eprintln!("[SOCKET ERROR]:\n{}", error);
             }
         }
     }
+
+    println!("ws client {} disconnected", jwt);
+    // The user's orders stay while another of their sockets is still connected, that session
+    // carries on with its own cancel-on-disconnect. Being dropped for lagging counts as a
+    // disconnect too.
+    let last_session = {
+        let mut socket_list = state.sockets.lock().await;
+        socket_list.remove(&session);
+        !socket_list.values().any(|subscriber| subscriber.jwt == jwt)
+    };
+
+    if cancel_on_disconnect && last_session && !jwt.is_empty() {
+        let cancel = MassCancel::all(jwt);
+        if let Err(e) = state
+            .book
+            .tx
+            .send(OrderBookMessage::MassCancel(cancel))
+            .await
+        {
+            eprintln!("[CANCEL ON DISCONNECT ERROR] {}", e);
+        }
+    }
 }
 
-async fn handle_websocket_message(socket: &mut WebSocket) -> Result<String, ()> {
-    if let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            if let Ok(text) = msg.to_text() {
-                if let Ok(ws_msg) = serde_json::from_str::<SocketMessageRecv>(text) {
-                    if ws_msg.event.as_str() == "jwt" {
-                        if let Some(jwt) = ws_msg.jwt {
-                            return Ok(jwt);
-                        }
-                    } else {
-                        println!("Unknown event: {}", ws_msg.event);
+// Reads the client's hello, returns its jwt and what it subscribed to.
+async fn handle_websocket_message(socket: &mut WebSocket) -> Result<(String, Subscription), ()> {
+    if let Some(Ok(msg)) = socket.recv().await {
+        if let Ok(text) = msg.to_text() {
+            if let Ok(ws_msg) = serde_json::from_str::<SocketMessageRecv>(text) {
+                if ws_msg.event.as_str() == "jwt" {
+                    if let Some(jwt) = ws_msg.jwt {
+                        let subscription = Subscription {
+                            symbol: ws_msg.symbol,
+                            channels: ws_msg
+                                .channels
+                                .unwrap_or_else(|| vec!["trades".to_string()]),
+                            cancel_on_disconnect: ws_msg.cancel_on_disconnect.unwrap_or(false),
+                        };
+                        return Ok((jwt, subscription));
                     }
+                } else {
+                    println!("Unknown event: {}", ws_msg.event);
                 }
             }
         }
     }
 
-    Ok("".to_string())
+    Ok((
+        "".to_string(),
+        Subscription {
+            symbol: None,
+            channels: vec!["trades".to_string()],
+            cancel_on_disconnect: false,
+        },
+    ))
 }
 
 pub async fn broadcast_trade(trade: Trade, sockets: Arc<Mutex<SocketList>>) {
-    let socket_list = sockets.lock().await;
-    for (_, socket_sender) in socket_list.iter() {
-        if let Err(err) = socket_sender
-            .send(SocketMessageSend::Trade(trade.clone()))
-            .await
-        {
-            eprintln!("{}", err);
+    let symbol = trade.symbol.clone();
+    broadcast(&sockets, "trades", &symbol, || {
+        SocketMessageSend::Trade(trade.clone())
+    })
+    .await;
+}
+
+pub async fn broadcast_market_data(data: MarketData, sockets: Arc<Mutex<SocketList>>) {
+    broadcast(&sockets, data.channel(), data.symbol(), || {
+        SocketMessageSend::MarketData(data.clone())
+    })
+    .await;
+}
+
+// Hands the message to every subscriber of `channel` and `symbol` without waiting on any of
+// them, so a slow client can't hold up the book or position thread behind the lock.
+async fn broadcast(
+    sockets: &Mutex<SocketList>,
+    channel: &str,
+    symbol: &str,
+    message: impl Fn() -> SocketMessageSend,
+) {
+    let mut socket_list = sockets.lock().await;
+    socket_list.retain(|_, subscriber| {
+        if !subscriber.subscription.wants(channel, symbol) {
+            return true;
         }
-    }
+        match subscriber.sender.try_send(message()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!("[SOCKET LAGGING] disconnecting {}", subscriber.jwt);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    });
 }
 
 // pub async fn broadcast_order(order: Order, sockets: Arc<Mutex<SocketList>>) {
@@ -3,25 +3,36 @@ mod handlers;
 mod state;
 
 mod types;
-use axum::{routing::any, routing::get, routing::post, Router};
+use axum::{routing::any, routing::delete, routing::get, routing::post, Router};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use tokio::time::{interval, Duration};
 
-use domain::order::OrderBook;
+use domain::instrument::INSTRUMENTS;
+use domain::market::Markets;
 use domain::position::EngineEvent;
 use domain::position::PositionTracker;
-use handlers::{broadcast_trade, handler, order_handler, ws_handler};
-use state::BookState;
+use handlers::{
+    amend_by_client_id_handler, amend_handler, brackets_handler, broadcast_market_data,
+    broadcast_trade, cancel_by_client_id_handler, cancel_handler, dead_man_switch_handler,
+    depth_handler, fee_tier_handler, handler, instruments_handler, l3_handler, maintenance_handler,
+    market_status_handler, mass_cancel_handler, oco_handler, order_handler,
+    order_status_by_client_id_handler, order_status_handler, orders_handler,
+    trigger_level_by_client_id_handler, trigger_level_handler, ws_handler,
+};
+use state::{BookState, PositionState, SocketState};
 
 use domain::Oracle;
 
 use domain::position::run_position_loop;
 use handlers::websocket::SocketList;
 
-use crate::domain::oracle::BtcPrice;
+use crate::domain::market_data::MarketData;
+use crate::domain::oracle::IndexPrice;
+use crate::domain::utils::now_secs;
+use crate::domain::wallet::BalanceUpdate;
 use crate::domain::wallet::WalletEvent;
 use crate::domain::wallet::WalletManager;
 use crate::domain::wallet::WalletOneshotReply;
@@ -38,25 +49,77 @@ async fn main() {
 
     let (position_tx, position_rx) = mpsc::unbounded_channel::<EngineEvent>();
 
-    let (oracle_tx, oracle_rx) = mpsc::unbounded_channel::<BtcPrice>();
+    let (oracle_tx, oracle_rx) = mpsc::unbounded_channel::<IndexPrice>();
 
-    let mut wallets = WalletManager::new();
     let (wallet_tx, mut wallet_rx) = mpsc::unbounded_channel::<WalletEvent>();
+    let (balance_tx, mut balance_rx) = mpsc::unbounded_channel::<BalanceUpdate>();
+    let mut wallets = WalletManager::new(balance_tx);
 
-    let mut book = OrderBook::new(position_tx.clone(), wallet_tx.clone());
-    let positions = PositionTracker::new(liquidation_order_queue_tx, wallet_tx.clone());
+    let (market_data_tx, mut market_data_rx) = mpsc::unbounded_channel::<MarketData>();
+
+    let trackers: HashMap<String, PositionTracker> = INSTRUMENTS
+        .iter()
+        .map(|instrument| {
+            (
+                instrument.symbol.to_string(),
+                PositionTracker::new(
+                    instrument.symbol,
+                    liquidation_order_queue_tx.clone(),
+                    wallet_tx.clone(),
+                ),
+            )
+        })
+        .collect();
     let sockets: Arc<Mutex<SocketList>> = Arc::new(Mutex::new(HashMap::new()));
 
     let book_state = BookState { tx: book_tx };
+    let position_state = PositionState {
+        tx: position_tx.clone(),
+    };
+    let socket_state = SocketState {
+        sockets: sockets.clone(),
+        book: book_state.clone(),
+    };
 
     let app: Router = Router::new()
         .route("/", get(handler))
+        .route("/instruments", get(instruments_handler))
+        .route("/instruments/{symbol}/status", get(market_status_handler))
+        .route(
+            "/instruments/{symbol}/maintenance",
+            post(maintenance_handler),
+        )
+        .route("/depth", get(depth_handler))
+        .route("/l3", get(l3_handler))
+        .route("/fees/tier", get(fee_tier_handler))
         .route("/order", post(order_handler))
+        .route("/order/oco", post(oco_handler))
+        .route("/order/{id}/trigger", get(trigger_level_handler))
+        .route("/order/{id}", delete(cancel_handler).patch(amend_handler))
+        .route(
+            "/order/client/{client_order_id}/trigger",
+            get(trigger_level_by_client_id_handler),
+        )
+        .route(
+            "/order/client/{client_order_id}",
+            delete(cancel_by_client_id_handler).patch(amend_by_client_id_handler),
+        )
+        .route("/orders", get(orders_handler).delete(mass_cancel_handler))
+        .route("/orders/cancel-after", post(dead_man_switch_handler))
+        .route("/orders/{id}", get(order_status_handler))
+        .route(
+            "/orders/client/{client_order_id}",
+            get(order_status_by_client_id_handler),
+        )
         .with_state(book_state)
+        .route("/position/brackets", post(brackets_handler))
+        .with_state(position_state)
         .route("/ws", any(ws_handler))
-        .with_state(sockets.clone());
+        .with_state(socket_state);
 
     // Orderbook thread
+    let book_position_tx = position_tx.clone();
+    let book_wallet_tx = wallet_tx.clone();
     std::thread::spawn(move || {
         let mini_runtime = tokio::runtime::Builder::new_current_thread()
             .thread_name("orderbook thread")
@@ -64,22 +127,89 @@ async fn main() {
             .build()
             .expect("Failed to create tokio runtime on book thread");
 
+        // the books share their balances through an Rc, so they have to be built on this thread
+        let mut markets = Markets::new(book_position_tx, book_wallet_tx, market_data_tx);
         mini_runtime.block_on(async move {
+            let mut expiry_interval = interval(Duration::from_secs(1));
             loop {
                 tokio::select! {
                     biased;
 
                     maybe_liquidation_message = liquidation_order_queue_rx.recv() => {
-                        if let Some(OrderBookMessage::Order(order)) = maybe_liquidation_message {
-                            println!("[LIQUIDATION] order: {}", order);
-                            book.insert_order(order).await;
+                        match maybe_liquidation_message {
+                            Some(OrderBookMessage::Order(order)) => {
+                                println!("[LIQUIDATION] order: {}", order);
+                                markets.insert_order(order);
+                            }
+                            Some(OrderBookMessage::MarkPrice { symbol, mark_price, index_price }) => {
+                                markets.update_mark_price(&symbol, mark_price, index_price);
+                            }
+                            _ => {}
                         }
                     }
 
+                    Some(update) = balance_rx.recv() => {
+                        markets.reconcile_balance(update);
+                    }
+
+                    _ = expiry_interval.tick() => {
+                        markets.expire_orders(now_secs());
+                        markets.advance_phases(now_secs());
+                        markets.recalculate_fee_tiers(now_secs());
+                        markets.fire_dead_man_switches(now_secs());
+                    }
+
                     maybe_order_message = book_rx.recv() => {
-                        if let Some(OrderBookMessage::Order(order)) = maybe_order_message {
-                            println!("[ORDER] {}", order);
-                            book.insert_order(order).await;
+                        match maybe_order_message {
+                            Some(OrderBookMessage::Order(order)) => {
+                                println!("[ORDER] {}", order);
+                                markets.insert_order(order);
+                            }
+                            Some(OrderBookMessage::Cancel(cancel)) => {
+                                println!("[CANCEL] {} by {}", cancel.id, cancel.user_id);
+                                markets.handle_cancel(cancel);
+                            }
+                            Some(OrderBookMessage::MassCancel(cancel)) => {
+                                println!("[MASS CANCEL] by {}", cancel.user_id);
+                                markets.handle_mass_cancel(cancel);
+                            }
+                            Some(OrderBookMessage::DeadManSwitch(switch)) => {
+                                markets.handle_dead_man_switch(switch);
+                            }
+                            Some(OrderBookMessage::Amend(amend)) => {
+                                println!("[AMEND] {} by {}", amend.id, amend.user_id);
+                                markets.handle_amend(amend);
+                            }
+                            Some(OrderBookMessage::Oco(legs)) => {
+                                let (first, second) = *legs;
+                                println!("[OCO] {} / {}", first, second);
+                                markets.insert_oco(first, second);
+                            }
+                            Some(OrderBookMessage::TriggerLevel(query)) => {
+                                markets.handle_trigger_level(query);
+                            }
+                            Some(OrderBookMessage::MarketStatus(query)) => {
+                                markets.handle_market_status(query);
+                            }
+                            Some(OrderBookMessage::Maintenance(query)) => {
+                                markets.handle_maintenance(query);
+                            }
+                            Some(OrderBookMessage::Depth(query)) => {
+                                markets.handle_depth(query);
+                            }
+                            Some(OrderBookMessage::L3(query)) => {
+                                markets.handle_l3(query);
+                            }
+                            Some(OrderBookMessage::FeeTier(query)) => {
+                                markets.handle_fee_tier(query);
+                            }
+                            Some(OrderBookMessage::OrderStatus(query)) => {
+                                markets.handle_order_status(query);
+                            }
+                            Some(OrderBookMessage::Orders(query)) => {
+                                markets.handle_orders(query);
+                            }
+                            _ => {}
                         }
                     }
                 }
@@ -87,6 +217,16 @@ async fn main() {
         });
     });
 
+    // Market data fan-out, keeps socket writes off the book thread
+    tokio::spawn({
+        let sockets = sockets.clone();
+        async move {
+            while let Some(data) = market_data_rx.recv().await {
+                broadcast_market_data(data, sockets.clone()).await;
+            }
+        }
+    });
+
     // Position tracker thread
     std::thread::spawn(move || {
         let mini_runtime = tokio::runtime::Builder::new_current_thread()
@@ -96,7 +236,7 @@ async fn main() {
             .expect("Failed to create tokio runtime on positions thread");
 
         mini_runtime.block_on(async move {
-            run_position_loop(oracle_rx, position_rx, positions, sockets).await;
+            run_position_loop(oracle_rx, position_rx, trackers, sockets).await;
         });
     });
 
@@ -125,7 +265,7 @@ async fn main() {
                                 message: oneshot_reply_message,
                             });
 
-                            if let Err(_) = sent {
+                            if sent.is_err() {
                                 println!("[WALLET THREAD ERROR] can't send oneshot reply");
                             }
                         }
@@ -133,6 +273,12 @@ async fn main() {
                     WalletEvent::Credit(message) => {
                         wallets.credit(message.wallet_id, message.amount)
                     }
+                    WalletEvent::Reserve(message) => {
+                        wallets.reserve(message.wallet_id, message.amount)
+                    }
+                    WalletEvent::Release(message) => {
+                        wallets.release(message.wallet_id, message.amount)
+                    }
                 }
             }
         });
@@ -142,13 +288,18 @@ async fn main() {
     tokio::spawn({
         async move {
             let mut interval = interval(Duration::from_millis(500));
-            let mut oracle = Oracle::new(None);
+            let mut oracles: Vec<Oracle> = INSTRUMENTS
+                .iter()
+                .map(|instrument| Oracle::new(instrument, None))
+                .collect();
             loop {
                 interval.tick().await;
-                let price = oracle.next_price();
-                if let Err(error) = oracle_tx.send(price) {
-                    //  TODO: maybe add a logging system instead of just printing error to terminal
-                    eprintln!("[ORACLE ERROR] {}", error);
+                for oracle in oracles.iter_mut() {
+                    let price = oracle.next_price();
+                    if let Err(error) = oracle_tx.send(price) {
+                        //  TODO: maybe add a logging system instead of just printing error to terminal
+                        eprintln!("[ORACLE ERROR] {}", error);
+                    }
                 }
             }
         }
@@ -1,8 +1,24 @@
-use tokio::sync::mpsc;
+use std::sync::Arc;
 
+use tokio::sync::{mpsc, Mutex};
+
+use crate::domain::position::EngineEvent;
+use crate::handlers::websocket::SocketList;
 use crate::types::OrderBookMessage;
 
 #[derive(Clone)]
 pub struct BookState {
     pub tx: mpsc::Sender<OrderBookMessage>,
 }
+
+#[derive(Clone)]
+pub struct PositionState {
+    pub tx: mpsc::UnboundedSender<EngineEvent>,
+}
+
+#[derive(Clone)]
+pub struct SocketState {
+    pub sockets: Arc<Mutex<SocketList>>,
+    // cancel-on-disconnect sends its mass cancel here
+    pub book: BookState,
+}
@@ -1,6 +1,16 @@
 use serde::{Deserialize, Serialize};
 
-use crate::domain::{position::Trade, Order};
+use crate::domain::{
+    fee_tier::FeeTierQuery,
+    market_data::{DepthQuery, L3Query, MarketData},
+    order::{
+        AmendOrder, CancelOrder, DeadManSwitch, MaintenanceQuery, MarketStatusQuery, MassCancel,
+        Price, TriggerLevelQuery,
+    },
+    order_history::{OrderStatusQuery, OrdersQuery},
+    position::Trade,
+    Order,
+};
 
 #[derive(Serialize)]
 pub struct Response {
@@ -10,24 +20,163 @@ pub struct Response {
 
 #[derive(Deserialize)]
 pub struct OrderRequest {
+    // instrument to trade, e.g. "BTC-PERP"
+    pub symbol: String,
     pub type_: String,
     pub amount: f64,
     pub price: f64,
     pub side: String,
     pub leverage: u32,
+    pub trigger_price: Option<f64>,
+    pub take_profit: Option<f64>,
+    pub stop_loss: Option<f64>,
+    pub time_in_force: Option<String>,
+    pub expires_at: Option<u64>,
+    pub post_only: Option<bool>,
+    // re-price a crossing post-only order one tick inside the spread instead of rejecting it
+    pub post_only_reprice: Option<bool>,
+    pub reduce_only: Option<bool>,
+    // iceberg: only this much of the order is shown in the book at a time
+    pub display_amount: Option<f64>,
+    // "cancel_taker", "cancel_maker" or "cancel_both"
+    pub self_trade_prevention: Option<String>,
+    // trailing stops: how far the trigger trails the mark, in price or in percent of the mark
+    pub trailing_offset: Option<f64>,
+    pub trailing_percent: Option<f64>,
+    // market orders: stop matching past this price, or this many bps off the best price
+    pub protection_price: Option<f64>,
+    pub max_slippage_bps: Option<f64>,
+    // the client's own id for the order, cancels, amends and queries can use it instead
+    pub client_order_id: Option<String>,
+    // retrying with the same key returns the first submission's result instead of placing a
+    // second order, keys are remembered for 24 hours
+    pub idempotency_key: Option<String>,
     pub jwt: String, // TODO
 }
 
+// two orders where a fill or trigger of either one cancels the other
+#[derive(Deserialize)]
+pub struct OcoRequest {
+    pub first: OrderRequest,
+    pub second: OrderRequest,
+}
+
+#[derive(Deserialize)]
+pub struct BracketRequest {
+    pub jwt: String,
+    pub symbol: String,
+    pub take_profit: Option<f64>,
+    pub stop_loss: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct CancelRequest {
+    pub jwt: String,
+}
+
+#[derive(Deserialize)]
+pub struct MassCancelRequest {
+    pub jwt: String,
+    pub symbol: Option<String>,
+    // "buy" or "sell"
+    pub side: Option<String>,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeadManSwitchRequest {
+    pub jwt: String,
+    // seconds until all orders get cancelled unless the switch is refreshed, 0 disarms it
+    pub timeout: u64,
+}
+
+#[derive(Deserialize)]
+pub struct TriggerLevelRequest {
+    pub jwt: String,
+}
+
+#[derive(Deserialize)]
+pub struct DepthRequest {
+    pub symbol: String,
+    pub levels: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceRequest {
+    // must match the ADMIN_TOKEN the server was started with
+    pub admin_token: String,
+    // seconds, the re-opening auction starts after them
+    pub duration: u64,
+}
+
+#[derive(Deserialize)]
+pub struct FeeTierRequest {
+    pub jwt: String,
+}
+
+#[derive(Deserialize)]
+pub struct OrderStatusRequest {
+    pub jwt: String,
+}
+
+#[derive(Deserialize)]
+pub struct OrdersRequest {
+    pub jwt: String,
+    // "open" (the default) or "closed" for recently finished orders
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct L3Request {
+    pub symbol: String,
+}
+
+#[derive(Deserialize)]
+pub struct AmendRequest {
+    pub jwt: String,
+    pub amount: Option<f64>,
+    pub price: Option<f64>,
+}
+
+// orders are by far the most common message, boxing them would cost an allocation per order
+#[allow(clippy::large_enum_variant)]
 pub enum OrderBookMessage {
     Order(Order),
+    Cancel(CancelOrder),
+    Amend(AmendOrder),
+    MassCancel(MassCancel),
+    DeadManSwitch(DeadManSwitch),
+    // boxed, two orders in one variant would make every message twice as big
+    Oco(Box<(Order, Order)>),
+    TriggerLevel(TriggerLevelQuery),
+    MarkPrice {
+        symbol: String,
+        mark_price: Price,
+        index_price: Price,
+    },
+    MarketStatus(MarketStatusQuery),
+    Maintenance(MaintenanceQuery),
+    Depth(DepthQuery),
+    L3(L3Query),
+    FeeTier(FeeTierQuery),
+    OrderStatus(OrderStatusQuery),
+    Orders(OrdersQuery),
 }
 
 pub enum SocketMessageSend {
     Trade(Trade),
+    MarketData(MarketData),
 }
 
 #[derive(Deserialize)]
 pub struct SocketMessageRecv {
     pub event: String,
     pub jwt: Option<String>,
+    // only receive data of this instrument, all of them when omitted
+    pub symbol: Option<String>,
+    // any of "trades", "depth", "l3" and "auction", just trades when omitted
+    pub channels: Option<Vec<String>>,
+    // cancel all of the user's orders when this socket disconnects
+    pub cancel_on_disconnect: Option<bool>,
 }
@@ -24,6 +24,7 @@ const (
 
 // keep it simple: one order shape
 type OrderRequest struct {
+	Symbol   string  `json:"symbol"`
 	Type     string  `json:"type_"`
 	Amount   float64 `json:"amount"`
 	Price    float64 `json:"price"`
@@ -83,8 +84,9 @@ func main() {
 
 					// keep values very simple & bounded
 					order := OrderRequest{
+						Symbol:   "BTC-PERP",
 						Type:     "limit",
-						Amount:   round6(0.01 + r.Float64()*0.99),  // 0.01..1.00
+						Amount:   round3(0.01 + r.Float64()*0.99),  // 0.01..1.00
 						Price:    round2(59000 + r.Float64()*2000), // ~59000..61000
 						Side:     side,
 						Leverage: 1 + r.Intn(10),                 // 1..10
@@ -161,7 +163,7 @@ func main() {
 }
 
 func round2(f float64) float64 { return float64(int64(f*100+0.5)) / 100 }
-func round6(f float64) float64 { return float64(int64(f*1e6+0.5)) / 1e6 }
+func round3(f float64) float64 { return float64(int64(f*1e3+0.5)) / 1e3 }
 func percent(a, total float64) float64 {
 	if total <= 0 {
 		return 0
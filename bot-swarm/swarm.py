@@ -0,0 +1,109 @@
+"""Python port of main.go for machines without a Go toolchain.
+
+Same load: `users` concurrent clients on one keep-alive connection each, posting
+BTC-PERP limit orders with a random side, size, price and leverage and a 5-20ms
+pause between orders, for `test_duration` seconds. Standard library only.
+
+    python3 swarm.py [seconds]
+"""
+
+import asyncio
+import json
+import random
+import sys
+import time
+
+# ----- tweakables -----
+HOST, PORT = "127.0.0.1", 8000  # your server
+USERS = 59  # concurrent clients
+TEST_DURATION = 2 * 60  # run time in seconds
+ORDER_ENDPOINT = "/order"
+
+stats = {"sent": 0, "ok": 0, "fail": 0, "buy": 0, "sell": 0, "latency": 0.0}
+
+
+async def read_response(reader):
+    head = await reader.readuntil(b"\r\n\r\n")
+    length = 0
+    for line in head.split(b"\r\n"):
+        if line.lower().startswith(b"content-length:"):
+            length = int(line.split(b":", 1)[1])
+    await reader.readexactly(length)
+    return int(head.split(b" ", 2)[1])
+
+
+async def user(user_id, deadline):
+    r = random.Random(time.time_ns() + user_id)
+    reader, writer = await asyncio.open_connection(HOST, PORT)
+
+    while time.monotonic() < deadline:
+        side = "sell" if r.randrange(2) == 0 else "buy"
+        # keep values very simple & bounded
+        body = json.dumps(
+            {
+                "symbol": "BTC-PERP",
+                "type_": "limit",
+                "amount": round(0.01 + r.random() * 0.99, 3),  # 0.01..1.00
+                "price": round(59000 + r.random() * 2000, 2),  # ~59000..61000
+                "side": side,
+                "leverage": 1 + r.randrange(10),  # 1..10
+                "jwt": f"user_{user_id}",  # placeholder "auth"
+            }
+        ).encode()
+        request = (
+            f"POST {ORDER_ENDPOINT} HTTP/1.1\r\n"
+            f"Host: {HOST}\r\n"
+            "Content-Type: application/json\r\n"
+            "Accept: application/json\r\n"
+            f"Content-Length: {len(body)}\r\n\r\n"
+        ).encode() + body
+
+        start = time.perf_counter()
+        try:
+            writer.write(request)
+            await writer.drain()
+            status = await read_response(reader)
+        except (OSError, asyncio.IncompleteReadError):
+            status = None
+        stats["latency"] += time.perf_counter() - start
+        stats["sent"] += 1
+        stats[side] += 1
+
+        if status == 200:
+            stats["ok"] += 1
+        else:
+            stats["fail"] += 1
+            if status is None:
+                writer.close()
+                reader, writer = await asyncio.open_connection(HOST, PORT)
+
+        await asyncio.sleep((5 + r.randrange(15)) / 1000)
+
+    writer.close()
+
+
+async def main():
+    duration = float(sys.argv[1]) if len(sys.argv) > 1 else TEST_DURATION
+    start = time.monotonic()
+    await asyncio.gather(*(user(u, start + duration) for u in range(USERS)))
+    wall = time.monotonic() - start
+
+    sent = stats["sent"]
+    buys, sells = stats["buy"], stats["sell"]
+    print("=== LOAD TEST SUMMARY ===")
+    print(f"Duration:          {wall:.3f}s")
+    print(f"Users (clients):   {USERS}")
+    print(f"Requests sent:     {sent}")
+    print(f"  - 200 OK:        {stats['ok']}")
+    print(f"  - Fail/Non-200:  {stats['fail']}")
+    print(f"Avg latency:       {stats['latency'] / max(sent, 1) * 1000:.2f} ms")
+    print(f"Throughput:        {sent / wall:.1f} req/s")
+    print(
+        f"Side split:        buys={buys}  sells={sells} "
+        f"(buy ratio {buys / max(buys + sells, 1) * 100:.1f}%)"
+    )
+    print("=========================")
+
+
+if __name__ == "__main__":
+    asyncio.run(main())